@@ -10,6 +10,8 @@ printf 'SCRIPT_DIR: %s\n' "$SCRIPT_DIR"
 
 # Update the settings for the dynamic_canisters_backend
 dfx canister update-settings --add-controller $(dfx canister id sns_root) dynamic_canisters_backend
+# Only SNS governance (or a controller) may update user canisters through the backend
+dfx canister call dynamic_canisters_backend set_sns_governance "(opt principal \"$(dfx canister id sns_governance)\")"
 
 # Extract the information
 DEVELOPER_NEURON_ID=$1
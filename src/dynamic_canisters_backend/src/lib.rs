@@ -1,8 +1,9 @@
 use ic_cdk::api;
-use ic_cdk::export::candid::{
-    candid_method, CandidType, check_prog, Deserialize, export_service, IDLProg, TypeEnv,
-};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 use std::cell::RefCell;
+use std::fmt;
+use user_profile::FieldError;
 
 
 #[ic_cdk::query]
@@ -20,23 +21,345 @@ thread_local! {
     static CHART_TICKS: RefCell<Vec<ChartTick>> = Default::default();
 }
 
+/// Heap state kept across upgrades.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    events: events::StableState,
+    wallet: wallet::StableState,
+    user: user::StableState,
+    top_up: top_up::StableState,
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        events: events::save_state(),
+        wallet: wallet::save_state(),
+        user: user::save_state(),
+        top_up: top_up::save_state(),
+    };
+    ic_cdk::storage::stable_save((state,)).expect("Failed to save state to stable memory");
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Versions without upgrade hooks left stable memory empty; they start from the defaults.
+    if api::stable::stable64_size() == 0 {
+        return;
+    }
+    let (state,): (StableState,) =
+        ic_cdk::storage::stable_restore().expect("Failed to restore state from stable memory");
+    events::restore_state(state.events);
+    wallet::restore_state(state.wallet);
+    user::restore_state(state.user);
+    top_up::restore_state(state.top_up);
+}
+
+fn is_controller() -> Result<(), String> {
+    if api::is_controller(&api::caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller of this canister".to_string())
+    }
+}
+
+/// Error returned by every backend endpoint. User canisters mirror it to decode top-up
+/// refusals, so a new variant has to be added to `BackendError` in user_canister too.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Error {
+    NotAuthorized,
+    NotFound(String),
+    InvalidArgument(String),
+    InsufficientCycles {
+        available: u128,
+        required: u128,
+    },
+    ManagementCallFailed {
+        method: String,
+        rejection_code: RejectionCode,
+        message: String,
+    },
+    CanisterCallFailed {
+        canister_id: Principal,
+        method: String,
+        rejection_code: RejectionCode,
+        message: String,
+    },
+    // A user canister accepted the call but answered with an error.
+    UserCanisterError(String),
+    DownloadFailed(Vec<String>),
+    InvalidWasm(String),
+    Validation(Vec<FieldError>),
+    SignupInProgress {
+        signup_id: u64,
+        stage: user::SignupStage,
+    },
+    // Another message is working on the same caller or canister; try again later.
+    Busy(String),
+    // A signup step failed; the signup can be resumed with `retry_signup`.
+    SignupFailed {
+        signup_id: u64,
+        error: Box<Error>,
+    },
+    // The top-up policy of the user does not allow a top-up now.
+    TopUpRefused(String),
+    // The wallet could not be set up; its canister is left empty, with its cycles.
+    WalletDeployFailed {
+        canister_id: Principal,
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    pub fn management_call(method: &str, rejection_code: RejectionCode, message: String) -> Self {
+        Error::ManagementCallFailed {
+            method: method.to_string(),
+            rejection_code,
+            message,
+        }
+    }
+
+    pub fn canister_call(canister_id: Principal, method: &str, rejection_code: RejectionCode, message: String) -> Self {
+        Error::CanisterCallFailed {
+            canister_id,
+            method: method.to_string(),
+            rejection_code,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotAuthorized => write!(f, "Caller is not authorized"),
+            Error::NotFound(what) => write!(f, "{} does not exist", what),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::InsufficientCycles { available, required } => {
+                write!(f, "Insufficient cycles: {} available, {} required", available, required)
+            }
+            Error::ManagementCallFailed { method, rejection_code, message } => {
+                write!(f, "Management canister call {} failed: {:?}: {}", method, rejection_code, message)
+            }
+            Error::CanisterCallFailed { canister_id, method, rejection_code, message } => {
+                write!(f, "Call to {}.{} failed: {:?}: {}", canister_id, method, rejection_code, message)
+            }
+            Error::UserCanisterError(message) => write!(f, "User canister error: {}", message),
+            Error::DownloadFailed(errors) => write!(f, "Failed to download wasm: {}", errors.join("; ")),
+            Error::InvalidWasm(message) => write!(f, "Invalid wasm: {}", message),
+            Error::Validation(errors) => write!(f, "Invalid user: {}", user_profile::describe(errors)),
+            Error::Busy(resource) => write!(f, "{} is busy with another operation", resource),
+            Error::SignupInProgress { signup_id, stage } => {
+                write!(f, "Signup {} is in progress ({:?})", signup_id, stage)
+            }
+            Error::SignupFailed { signup_id, error } => write!(f, "Signup {} failed: {}", signup_id, error),
+            Error::TopUpRefused(reason) => write!(f, "Top-up refused: {}", reason),
+            Error::WalletDeployFailed { canister_id, error } => {
+                write!(f, "Wallet deployment to {} failed: {}", canister_id, error)
+            }
+        }
+    }
+}
+
+/// Like the `is_controller` guard, for endpoints that report failures as [`Error`].
+fn ensure_controller() -> Result<(), Error> {
+    if api::is_controller(&api::caller()) {
+        Ok(())
+    } else {
+        Err(Error::NotAuthorized)
+    }
+}
+
+/// Fail early instead of letting the management canister reject a call we cannot pay for.
+fn ensure_cycles(required: u128) -> Result<(), Error> {
+    let available = api::canister_balance128();
+    if available < required {
+        return Err(Error::InsufficientCycles { available, required });
+    }
+    Ok(())
+}
+
 fn update_chart() {
     let timestamp = api::time();
     let cycles = api::canister_balance();
-    CHART_TICKS.with(|mut chart| chart.borrow_mut().push(ChartTick { timestamp, cycles }));
+    CHART_TICKS.with(|chart| chart.borrow_mut().push(ChartTick { timestamp, cycles }));
+}
+
+mod locks {
+    use ic_cdk::api;
+    use ic_cdk::export::candid::Principal;
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+    use std::fmt;
+
+    use super::Error;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Resource {
+        Caller(Principal),
+        Canister(Principal),
+    }
+
+    impl fmt::Display for Resource {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Resource::Caller(principal) => write!(f, "Caller {}", principal),
+                Resource::Canister(principal) => write!(f, "Canister {}", principal),
+            }
+        }
+    }
+
+    thread_local! {
+        static HELD: RefCell<BTreeSet<Resource>> = Default::default();
+    }
+
+    /// Exclusive access to a resource across the awaits of one message, released on drop.
+    ///
+    /// ic-cdk drops the future of a message that traps after an await, so a lock held by
+    /// that message is released as well.
+    #[must_use]
+    pub struct Lock(Resource);
+
+    impl Lock {
+        pub fn acquire(resource: Resource) -> Result<Self, Error> {
+            if HELD.with(|held| held.borrow_mut().insert(resource)) {
+                Ok(Self(resource))
+            } else {
+                Err(Error::Busy(resource.to_string()))
+            }
+        }
+
+        pub fn caller() -> Result<Self, Error> {
+            Self::acquire(Resource::Caller(api::caller()))
+        }
+
+        pub fn canister(canister_id: Principal) -> Result<Self, Error> {
+            Self::acquire(Resource::Canister(canister_id))
+        }
+    }
+
+    impl Drop for Lock {
+        fn drop(&mut self) {
+            HELD.with(|held| held.borrow_mut().remove(&self.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn lock_is_exclusive_until_dropped() {
+            let canister = Principal::management_canister();
+            let lock = Lock::canister(canister).unwrap();
+            assert!(matches!(Lock::canister(canister), Err(Error::Busy(_))));
+            assert!(Lock::acquire(Resource::Caller(canister)).is_ok());
+            drop(lock);
+            assert!(Lock::canister(canister).is_ok());
+        }
+    }
+}
+
+mod events {
+    use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+    use ic_cdk::{api, query};
+    use std::cell::RefCell;
+
+    use super::is_controller;
+
+    #[derive(CandidType, Clone, Deserialize)]
+    pub enum EventKind {
+        CanisterCreated {
+            canister: Principal,
+            cycles: u128,
+        },
+        WalletDeployed {
+            canister: Principal,
+        },
+        UserCanisterMigrated {
+            from: Principal,
+            to: Principal,
+        },
+        UserCanisterGraduated {
+            canister: Principal,
+            owner: Principal,
+        },
+        UserCanisterToppedUp {
+            canister: Principal,
+            cycles: u128,
+        },
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    pub struct Event {
+        pub id: u32,
+        pub timestamp: u64,
+        pub kind: EventKind,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct GetEventsArgs {
+        from: Option<u32>,
+        to: Option<u32>,
+    }
+
+    thread_local! {
+        static EVENTS: RefCell<Vec<Event>> = Default::default();
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct StableState {
+        events: Vec<Event>,
+    }
+
+    pub fn save_state() -> StableState {
+        StableState {
+            events: EVENTS.with(|events| events.borrow().clone()),
+        }
+    }
+
+    pub fn restore_state(state: StableState) {
+        EVENTS.with(|events| events.replace(state.events));
+    }
+
+    /// Append an event to the log.
+    pub fn record(kind: EventKind) {
+        EVENTS.with(|events| {
+            let mut events = events.borrow_mut();
+            let id = events.len() as u32;
+            events.push(Event {
+                id,
+                timestamp: api::time(),
+                kind,
+            });
+        });
+    }
+
+    /// Return the events with ids in `[from, to)`, or the whole log when no bounds are given.
+    #[query(name = "get_events", guard = "is_controller")]
+    fn get_events(args: Option<GetEventsArgs>) -> Vec<Event> {
+        let GetEventsArgs { from, to } = args.unwrap_or(GetEventsArgs { from: None, to: None });
+        EVENTS.with(|events| {
+            let events = events.borrow();
+            let from = from.unwrap_or(0) as usize;
+            let to = to.map_or(events.len(), |to| (to as usize).min(events.len()));
+            events.get(from..to).map_or_else(Vec::new, |events| events.to_vec())
+        })
+    }
 }
 
 mod wallet {
-    use ic_cdk::export::candid::{
-        candid_method, CandidType, check_prog, Deserialize, export_service, IDLProg, TypeEnv,
-    };
+    use ic_cdk::export::candid::{candid_method, CandidType, Deserialize};
     // use ic_cdk::query;
     use std::convert::TryInto;
 
     use ic_cdk::*;
     use ic_cdk::export::candid::{Nat};
     use ic_cdk::export::Principal;
-    use super::*;
+    use std::cell::RefCell;
+    use super::locks::Lock;
+    use ic_cdk::api::management_canister::main::{uninstall_code, CanisterIdRecord};
+    use super::{ensure_controller, ensure_cycles, events, is_controller, Error};
 
     /***************************************************************************************************
              * Cycle Management
@@ -46,6 +369,7 @@ mod wallet {
         amount: TCycles,
     }
 
+    #[allow(dead_code)]
     #[derive(CandidType, Deserialize)]
     struct SendCyclesArgs<TCycles> {
         canister: Principal,
@@ -97,6 +421,7 @@ mod wallet {
         settings: CanisterSettings,
     }
 
+    #[allow(dead_code)]
     #[derive(CandidType, Deserialize)]
     struct UpdateSettingsArgs {
         canister_id: Principal,
@@ -113,7 +438,7 @@ mod wallet {
     #[ic_cdk::update]
     async fn create_canister(
         CreateCanisterArgs { cycles, settings }: CreateCanisterArgs<u64>,
-    ) -> Result<CreateResult, String> {
+    ) -> Result<CreateResult, Error> {
         create_canister128(CreateCanisterArgs {
             cycles: cycles as u128,
             settings,
@@ -121,7 +446,7 @@ mod wallet {
             .await
     }
 
-    async fn create_canister_call(args: CreateCanisterArgs<u128>) -> Result<CreateResult, String> {
+    async fn create_canister_call(args: CreateCanisterArgs<u128>) -> Result<CreateResult, Error> {
         #[derive(CandidType)]
         struct In {
             settings: Option<CanisterSettings>,
@@ -130,6 +455,7 @@ mod wallet {
             settings: Some(normalize_canister_settings(args.settings)?),
         };
 
+        ensure_cycles(args.cycles)?;
         let (create_result, ): (CreateResult, ) = match api::call::call_with_payment128(
             Principal::management_canister(),
             "create_canister",
@@ -140,17 +466,14 @@ mod wallet {
         {
             Ok(x) => x,
             Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ));
+                return Err(Error::management_call("create_canister", code, msg));
             }
         };
 
-        // events::record(events::EventKind::CanisterCreated {
-        //     canister: create_result.canister_id,
-        //     cycles: args.cycles,
-        // });
+        events::record(events::EventKind::CanisterCreated {
+            canister: create_result.canister_id,
+            cycles: args.cycles,
+        });
         Ok(create_result)
     }
 
@@ -162,7 +485,8 @@ mod wallet {
     #[ic_cdk::update]
     async fn create_canister128(
         mut args: CreateCanisterArgs<u128>,
-    ) -> Result<CreateResult, String> {
+    ) -> Result<CreateResult, Error> {
+        let _lock = Lock::caller()?;
         let mut settings = normalize_canister_settings(args.settings)?;
         let controllers = settings
             .controllers
@@ -178,13 +502,13 @@ mod wallet {
     }
 
     // Make it so the controller or controllers are stored only in the controllers field.
-    fn normalize_canister_settings(settings: CanisterSettings) -> Result<CanisterSettings, String> {
+    fn normalize_canister_settings(settings: CanisterSettings) -> Result<CanisterSettings, Error> {
         // Agent <= 0.8.0, dfx <= 0.8.1 will send controller
         // Agents >= 0.9.0, dfx >= 0.8.2 will send controllers
         // The management canister will accept either controller or controllers, but not both.
         match (&settings.controller, &settings.controllers) {
             (Some(_), Some(_)) => {
-                Err("CanisterSettings cannot have both controller and controllers set.".to_string())
+                Err(Error::InvalidArgument("CanisterSettings cannot have both controller and controllers set.".to_string()))
             }
             (Some(controller), None) => Ok(CanisterSettings {
                 controller: None,
@@ -196,7 +520,7 @@ mod wallet {
     }
 
 
-    async fn install_wallet(canister_id: &Principal, wasm_module: Vec<u8>) -> Result<(), String> {
+    async fn install_wallet(canister_id: &Principal, wasm_module: Vec<u8>) -> Result<(), Error> {
         // Install Wasm
         #[derive(CandidType, Deserialize)]
         enum InstallMode {
@@ -224,7 +548,7 @@ mod wallet {
             arg: b" ".to_vec(),
         };
 
-        match api::call::call(
+        match api::call::call::<_, ()>(
             Principal::management_canister(),
             "install_code",
             (install_config, ),
@@ -233,16 +557,10 @@ mod wallet {
         {
             Ok(x) => x,
             Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ));
+                return Err(Error::management_call("install_code", code, msg));
             }
         };
 
-        // events::record(events::EventKind::WalletDeployed {
-        //     canister: *canister_id,
-        // });
         #[derive(CandidType, Deserialize)]
         struct WalletStoreWASMArgs {
             #[serde(with = "serde_bytes")]
@@ -251,37 +569,117 @@ mod wallet {
 
         // Store wallet wasm
         let store_args = WalletStoreWASMArgs { wasm_module };
-        match api::call::call(*canister_id, "wallet_store_wallet_wasm", (store_args, )).await {
+        match api::call::call::<_, ()>(*canister_id, "wallet_store_wallet_wasm", (store_args, )).await {
             Ok(x) => x,
             Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ));
+                return Err(Error::canister_call(*canister_id, "wallet_store_wallet_wasm", code, msg));
             }
         };
         Ok(())
     }
 
+    /***************************************************************************************************
+     * Wallet Deployment
+     **************************************************************************************************/
+    thread_local! {
+        static WALLET_WASM: RefCell<Option<Vec<u8>>> = Default::default();
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct StableState {
+        wallet_wasm: Option<Vec<u8>>,
+    }
+
+    pub fn save_state() -> StableState {
+        StableState {
+            wallet_wasm: WALLET_WASM.with(|wasm| wasm.borrow().clone()),
+        }
+    }
+
+    pub fn restore_state(state: StableState) {
+        WALLET_WASM.with(|wasm| wasm.replace(state.wallet_wasm));
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct StoreWalletWasmArgs {
+        #[serde(with = "serde_bytes")]
+        wasm_module: Vec<u8>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct DeployWalletArgs {
+        cycles: u128,
+        // Defaults to the caller.
+        custodian: Option<Principal>,
+    }
+
+    /// Store the cycles wallet module that `deploy_wallet` installs.
+    #[candid_method(update)]
+    #[ic_cdk::update(guard = "is_controller")]
+    fn store_wallet_wasm(args: StoreWalletWasmArgs) {
+        WALLET_WASM.with(|wasm| *wasm.borrow_mut() = Some(args.wasm_module));
+    }
+
+    /// Create a canister, install the stored cycles wallet on it and make the custodian its owner.
+    #[candid_method(update)]
+    #[ic_cdk::update]
+    async fn deploy_wallet(args: DeployWalletArgs) -> Result<CreateResult, Error> {
+        ensure_controller()?;
+        let wasm_module = WALLET_WASM
+            .with(|wasm| wasm.borrow().clone())
+            .ok_or_else(|| Error::NotFound("Wallet wasm".to_string()))?;
+        let custodian = args.custodian.unwrap_or_else(api::caller);
+
+        let create_result = create_canister128(CreateCanisterArgs {
+            cycles: args.cycles,
+            settings: CanisterSettings {
+                controller: None,
+                controllers: Some(vec![custodian, api::id()]),
+                compute_allocation: None,
+                memory_allocation: None,
+                freezing_threshold: None,
+            },
+        })
+            .await?;
+        let canister_id = create_result.canister_id;
+        if let Err(error) = set_up_wallet(canister_id, wasm_module, custodian).await {
+            // Best effort: a half set up wallet is removed, and the custodian, who controls the
+            // canister, can reuse it or take its cycles back.
+            let _ = uninstall_code(CanisterIdRecord { canister_id }).await;
+            return Err(Error::WalletDeployFailed {
+                canister_id,
+                error: Box::new(error),
+            });
+        }
+        events::record(events::EventKind::WalletDeployed { canister: canister_id });
+        Ok(create_result)
+    }
+
+    async fn set_up_wallet(canister_id: Principal, wasm_module: Vec<u8>, custodian: Principal) -> Result<(), Error> {
+        install_wallet(&canister_id, wasm_module).await?;
+        // The wallet makes its installer a custodian; hand it over to the requested one as well.
+        api::call::call::<_, ()>(canister_id, "authorize", (custodian, ))
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "authorize", code, msg))
+    }
+
 
     #[cfg(test)]
     mod tests {
         use std::borrow::Cow;
         use std::env;
         use std::fs::{create_dir_all, write};
-        use std::path::Path;
         use std::path::PathBuf;
-        use candid::export_service;
 
-        use ic_cdk::{api, update};
-        use ic_cdk::api::management_canister::main::CanisterSettings;
         // use ic_cdk::export::candid::{
         //     candid_method, CandidType, check_prog, Deserialize, export_service, IDLProg, TypeEnv,
         // };
-        use ic_cdk::export::candid::Principal;
         use crate::wallet::BalanceResult;
         use crate::wallet::CreateCanisterArgs;
         use crate::wallet::CreateResult;
+        use crate::wallet::DeployWalletArgs;
+        use crate::wallet::StoreWalletWasmArgs;
+        use crate::Error;
         // use super::*;
 
         #[test]
@@ -300,7 +698,7 @@ mod wallet {
                 Err(e) => println!("Failed to create directory: {}", e),
             }
 
-            let res = write(dir.join(format!("{:?}.did", canister_name).replace("\"", "")), export_candid());
+            let _res = write(dir.join(format!("{:?}.did", canister_name).replace("\"", "")), export_candid());
             println!("-------- Wrote to {:?}", dir);
             println!("-------- res {:?}", canister_name);
         }
@@ -308,24 +706,218 @@ mod wallet {
 }
 
 mod user {
-    use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument, HttpMethod};
+    use ic_cdk::api::management_canister::http_request::{
+        http_request_with_cycles, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+        TransformContext,
+    };
+    use ic_cdk::api::management_canister::main::{
+        uninstall_code, update_settings, CanisterIdRecord, CanisterSettings, CanisterStatusType, UpdateSettingsArgument,
+    };
     use ic_cdk::export::candid::{CandidType, Principal, Nat};
     use ic_cdk::{api, query, update};
+    use candid::utils::{service_compatible, CandidSource};
+    use libflate::gzip;
+    use num_traits::ToPrimitive;
+    use sha2::{Digest, Sha256};
+    use std::borrow::Cow;
     use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Read;
+    use std::ops::{Bound, Range};
+    use user_profile::{validate_patch, validate_user, FieldError};
     use serde::{Serialize, Deserialize};
+
+    use super::locks::Lock;
+    use super::{ensure_controller, ensure_cycles, is_controller, Error};
     
     thread_local! {
-        static USER_CANISTERS: RefCell<Vec<Principal>> = Default::default();
+        static USER_CANISTERS: RefCell<BTreeMap<Principal, UserCanister>> = Default::default();
+        static USER_WASM_VERSIONS: RefCell<BTreeMap<String, RegisteredWasm>> = Default::default();
+        static WASM_DOWNLOAD_CONFIG: RefCell<WasmDownloadConfig> = RefCell::new(WasmDownloadConfig::default());
+        static USER_WASM_SOURCE: RefCell<UserWasmSource> = Default::default();
+        static SNS_GOVERNANCE: RefCell<Option<Principal>> = Default::default();
+        static SIGNUPS: RefCell<BTreeMap<u64, Signup>> = Default::default();
+        static NEXT_SIGNUP_ID: RefCell<u64> = Default::default();
+        // Signup requested by each (caller, request id) pair.
+        static SIGNUP_REQUESTS: RefCell<BTreeMap<(Principal, String), u64>> = Default::default();
+        static RUNNING_SIGNUPS: RefCell<BTreeSet<u64>> = Default::default();
+        // Emptied canisters of rolled back signups, handed out before creating new ones.
+        static SPARE_CANISTERS: RefCell<Vec<Principal>> = Default::default();
+    }
+
+    // Everything above except `RUNNING_SIGNUPS`, which only lives as long as a message.
+    #[derive(CandidType, Deserialize)]
+    pub struct StableState {
+        user_canisters: BTreeMap<Principal, UserCanister>,
+        user_wasm_versions: BTreeMap<String, RegisteredWasm>,
+        wasm_download_config: WasmDownloadConfig,
+        user_wasm_source: UserWasmSource,
+        sns_governance: Option<Principal>,
+        signups: BTreeMap<u64, Signup>,
+        next_signup_id: u64,
+        signup_requests: BTreeMap<(Principal, String), u64>,
+        spare_canisters: Vec<Principal>,
+    }
+
+    pub fn save_state() -> StableState {
+        StableState {
+            user_canisters: USER_CANISTERS.with(|state| state.borrow().clone()),
+            user_wasm_versions: USER_WASM_VERSIONS.with(|state| state.borrow().clone()),
+            wasm_download_config: WASM_DOWNLOAD_CONFIG.with(|state| state.borrow().clone()),
+            user_wasm_source: USER_WASM_SOURCE.with(|state| state.borrow().clone()),
+            sns_governance: SNS_GOVERNANCE.with(|state| *state.borrow()),
+            signups: SIGNUPS.with(|state| state.borrow().clone()),
+            next_signup_id: NEXT_SIGNUP_ID.with(|state| *state.borrow()),
+            signup_requests: SIGNUP_REQUESTS.with(|state| state.borrow().clone()),
+            spare_canisters: SPARE_CANISTERS.with(|state| state.borrow().clone()),
+        }
+    }
+
+    pub fn restore_state(state: StableState) {
+        USER_CANISTERS.with(|current| current.replace(state.user_canisters));
+        USER_WASM_VERSIONS.with(|current| current.replace(state.user_wasm_versions));
+        WASM_DOWNLOAD_CONFIG.with(|current| current.replace(state.wasm_download_config));
+        USER_WASM_SOURCE.with(|current| current.replace(state.user_wasm_source));
+        SNS_GOVERNANCE.with(|current| current.replace(state.sns_governance));
+        SIGNUPS.with(|current| current.replace(state.signups));
+        NEXT_SIGNUP_ID.with(|current| current.replace(state.next_signup_id));
+        SIGNUP_REQUESTS.with(|current| current.replace(state.signup_requests));
+        SPARE_CANISTERS.with(|current| current.replace(state.spare_canisters));
     }
     
     static USER_CANISTER_WASM_MODULE_URL: &str = "https://localhost:3000/user_canister.wasm";
 
+    /// The interface `signup_new_user` and friends rely on.
+    const USER_CANISTER_DID: &str = include_str!("../../user_canister/user_canister.did");
+
+    const WASM_MAGIC: &[u8] = b"\0asm";
+    const WASM_VERSION: &[u8] = &[1, 0, 0, 0];
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    /// Upper bound on the size of a decompressed `.wasm.gz` module.
+    const MAX_DECOMPRESSED_WASM_SIZE: u64 = 100 * 1024 * 1024;
+
+    /// Cycles a new user canister is created with.
+    const USER_CANISTER_CYCLES: u128 = 100_000_000_000;
+    const MAX_REGISTRY_PAGE_SIZE: u64 = 100;
+    /// Name of the metadata section user canister modules describe their build in.
+    const VERSION_METADATA: &str = "user_canister:version";
+    /// A signup whose steps failed this many times is rolled back.
+    const MAX_SIGNUP_ATTEMPTS: u32 = 3;
+
+    /// Hard limit on the response size of an HTTP outcall.
+    const MAX_HTTP_RESPONSE_BYTES: u64 = 2_000_000;
+    /// Room left for the response headers on top of the expected wasm size.
+    const RESPONSE_HEADERS_ALLOWANCE: u64 = 16 * 1024;
+
+    /// Modules (plus init argument) above this size are installed in chunks, leaving headroom
+    /// for the rest of the `install_code` payload under the 2 MiB inter-canister message limit.
+    const MAX_SINGLE_INSTALL_SIZE: usize = 1_900_000;
+    /// Maximum chunk size accepted by the management canister's `upload_chunk`.
+    const WASM_CHUNK_SIZE: usize = 1024 * 1024;
+
     #[derive(Default, PartialEq, Eq, Serialize, CandidType, Deserialize, Clone, Debug)]
     struct User {
         name: String,
         age: u64,
         email: String
     }
+    impl User {
+        fn validate(&self) -> Result<(), Error> {
+            validate_user(&self.name, self.age, &self.email).map_err(Error::Validation)
+        }
+    }
+
+    // Mirrors `UserError` of the user canister.
+    #[derive(CandidType, Deserialize)]
+    enum UserError {
+        NotAuthorized,
+        NotFound,
+        Validation(Vec<FieldError>),
+        InvalidKey(String),
+        QuotaExceeded { quota: u64, required: u64 },
+        UnsupportedFormat(u32),
+        ReadOnly,
+    }
+
+    impl UserError {
+        fn into_error(self, canister_id: Principal) -> Error {
+            match self {
+                UserError::NotAuthorized => Error::UserCanisterError(format!(
+                    "User canister {} does not accept writes from this canister",
+                    canister_id
+                )),
+                UserError::NotFound => Error::NotFound(format!("User in canister {}", canister_id)),
+                UserError::Validation(errors) => Error::Validation(errors),
+                UserError::InvalidKey(message) => {
+                    Error::UserCanisterError(format!("User canister {} rejected a key: {}", canister_id, message))
+                }
+                UserError::QuotaExceeded { quota, required } => Error::UserCanisterError(format!(
+                    "User canister {} needs {} bytes of storage but its quota is {}",
+                    canister_id, required, quota
+                )),
+                UserError::UnsupportedFormat(version) => Error::UserCanisterError(format!(
+                    "User canister {} does not support data format {}",
+                    canister_id, version
+                )),
+                UserError::ReadOnly => Error::Busy(format!("User canister {} is being migrated", canister_id)),
+            }
+        }
+    }
+
+    // Mirrors the export types of the user canister.
+    #[derive(CandidType, Clone, Deserialize)]
+    struct DocumentId {
+        namespace: String,
+        key: String,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct ExportArgs {
+        start_after: Option<DocumentId>,
+        max_bytes: Option<u64>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct ExportedDocument {
+        namespace: String,
+        key: String,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct UserData {
+        format_version: u32,
+        schema_version: u32,
+        profile: Option<User>,
+        storage_quota: Option<u64>,
+        public_profile: Option<bool>,
+        documents: Vec<ExportedDocument>,
+        next: Option<DocumentId>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct UpdateUserArgs {
+        name: Option<String>,
+        age: Option<u64>,
+        email: Option<String>,
+    }
+
+    // Mirrors `Access` of the user canister.
+    #[derive(CandidType, Deserialize)]
+    struct UserCanisterAccess {
+        owner: Principal,
+        backend: Principal,
+        governance: Option<Principal>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct UserCanisterInitArgs {
+        owner: Principal,
+        governance: Option<Principal>,
+        storage_quota: Option<u64>,
+    }
+
     #[derive(CandidType, Serialize, Deserialize)]
     struct CreateUserArgs {
         user: User
@@ -350,6 +942,205 @@ mod user {
         canister_id: Principal,
     }
 
+    #[derive(CandidType, Clone, Deserialize)]
+    struct WasmDownloadConfig {
+        // Primary URL first, followed by mirrors tried in order on failure.
+        urls: Vec<String>,
+        expected_size: u64,
+        max_attempts: u32,
+        subnet_size: u32,
+    }
+
+    impl Default for WasmDownloadConfig {
+        fn default() -> Self {
+            Self {
+                urls: vec![USER_CANISTER_WASM_MODULE_URL.to_string()],
+                expected_size: 1_500_000,
+                max_attempts: 3,
+                subnet_size: 13,
+            }
+        }
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct AssetSource {
+        canister_id: Principal,
+        key: String,
+    }
+
+    /// Where `signup_new_user` gets the user canister module from.
+    #[derive(CandidType, Clone, Default, Deserialize)]
+    enum UserWasmSource {
+        // Download over HTTP outcalls as described by the wasm download config.
+        #[default]
+        Http,
+        // Read an asset, e.g. "/user_canister.wasm", from an asset canister.
+        AssetCanister(AssetSource),
+    }
+
+    /// Steps of a signup, in order. `RolledBack` is terminal for signups that were given up.
+    #[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+    pub enum SignupStage {
+        Started,
+        Created,
+        Installed,
+        Initialized,
+        Registered,
+        RolledBack,
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct Signup {
+        id: u64,
+        owner: Principal,
+        // Written to the canister by `create_user`; empty once the signup is registered.
+        user: User,
+        stage: SignupStage,
+        canister_id: Option<Principal>,
+        // When the canister was created, or taken from the spare pool.
+        created_at: Option<u64>,
+        // Registered version of the installed module, if it is one.
+        wasm_version: Option<String>,
+        attempts: u32,
+        last_error: Option<Error>,
+        started_at: u64,
+        updated_at: u64,
+    }
+
+    impl Signup {
+        fn is_orphaned(&self) -> bool {
+            self.canister_id.is_some() && self.stage != SignupStage::Registered
+        }
+
+        /// Record a completed step. The profile is dropped once the canister is registered, so
+        /// the backend keeps no copy that goes stale or outlives the account.
+        fn complete_step(&mut self, stage: SignupStage, canister_id: Option<Principal>) {
+            self.stage = stage;
+            self.canister_id = canister_id;
+            self.last_error = None;
+            if stage == SignupStage::Registered {
+                self.user = User::default();
+            }
+        }
+    }
+
+    /// Registry entry of a user canister. Status and cycles are as of the last fleet refresh.
+    #[derive(CandidType, Clone, Deserialize)]
+    struct UserCanister {
+        canister_id: Principal,
+        owner: Principal,
+        created_at: u64,
+        wasm_version: Option<String>,
+        code_version: Option<UserCanisterVersion>,
+        status: Option<CanisterStatusType>,
+        cycles: Option<Nat>,
+        checked_at: Option<u64>,
+        // Set once the owner became the sole controller; the backend no longer manages it.
+        self_custodied_at: Option<u64>,
+    }
+
+    #[derive(CandidType, Default, Deserialize)]
+    struct UserCanisterFilter {
+        owner: Option<Principal>,
+        wasm_version: Option<String>,
+        status: Option<CanisterStatusType>,
+    }
+
+    impl UserCanisterFilter {
+        fn matches(&self, canister: &UserCanister) -> bool {
+            self.owner.is_none_or(|owner| owner == canister.owner)
+                && self
+                    .wasm_version
+                    .as_ref()
+                    .is_none_or(|version| canister.wasm_version.as_ref() == Some(version))
+                && self.status.is_none_or(|status| canister.status == Some(status))
+        }
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct UserCanisterPage {
+        entries: Vec<UserCanister>,
+        // Pass as `cursor` to get the next page; absent on the last page.
+        next_cursor: Option<Principal>,
+    }
+
+    #[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq)]
+    enum InstallMode {
+        #[serde(rename = "install")]
+        Install,
+        #[serde(rename = "reinstall")]
+        Reinstall,
+        #[serde(rename = "upgrade")]
+        Upgrade,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct RegisterUserWasmArgs {
+        version: String,
+        #[serde(with = "serde_bytes")]
+        wasm_module: Vec<u8>,
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct UserWasmInfo {
+        version: String,
+        // Build information embedded in the module, if any.
+        code_version: Option<UserCanisterVersion>,
+        gzipped: bool,
+        // Hash of the module as stored and installed, i.e. what `canister_status` reports.
+        sha256: Vec<u8>,
+        // Hash of the uncompressed wasm.
+        wasm_sha256: Vec<u8>,
+        size: u64,
+    }
+
+    /// What a user canister reports from `version()` and embeds as `user_canister:version` metadata.
+    #[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+    pub struct UserCanisterVersion {
+        version: String,
+        git_commit: String,
+        schema_version: u32,
+    }
+
+    /// What a user canister reports from `health()`.
+    #[derive(CandidType, Clone, Deserialize)]
+    pub struct UserCanisterHealth {
+        has_profile: bool,
+        documents: u64,
+        document_bytes: u64,
+        heap_memory_bytes: u64,
+        stable_memory_bytes: u64,
+        cycles: u128,
+        last_top_up: Option<TopUpAttempt>,
+    }
+
+    /// Mirror of the user canister's `TopUpAttempt`.
+    #[derive(CandidType, Clone, Deserialize)]
+    pub struct TopUpAttempt {
+        at: u64,
+        balance: u128,
+        outcome: Result<u128, String>,
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct RegisteredWasm {
+        info: UserWasmInfo,
+        #[serde(with = "serde_bytes")]
+        module: Vec<u8>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct InstallUserCanisterArgs {
+        canister_id: Principal,
+        mode: InstallMode,
+        version: String,
+        // Candid encoded init or upgrade argument. Installs and reinstalls left empty get the
+        // owner and governance from the registry.
+        #[serde(with = "serde_bytes")]
+        arg: Vec<u8>,
+    }
+
+    #[allow(dead_code)]
     #[derive(Debug, CandidType, Deserialize)]
     struct QueryError {
         message: String,
@@ -358,7 +1149,7 @@ mod user {
     #[update(name = "user_create_canister")]
     async fn create_canister(
         UserCreateCanisterArgs { cycles, settings}: UserCreateCanisterArgs<u64>
-    ) -> Result<UserCreateCanisterResult, String> {
+    ) -> Result<UserCreateCanisterResult, Error> {
         create_canister128(UserCreateCanisterArgs {
             cycles: cycles as u128,
             settings,
@@ -368,7 +1159,7 @@ mod user {
     #[update(name = "user_create_canister128")]
     async fn create_canister128(
         mut args: UserCreateCanisterArgs<u128>,
-    ) -> Result<UserCreateCanisterResult, String> {
+    ) -> Result<UserCreateCanisterResult, Error> {
         let mut settings = args.settings;
         let mut controllers = settings.controllers.unwrap_or(vec![]);
         if controllers.is_empty() {
@@ -382,71 +1173,548 @@ mod user {
         Ok(create_canister_result)
     }
 
+    /// Create, install, initialize and register a user canister for the caller.
+    ///
+    /// Progress is recorded as a signup so that a failed signup can be resumed with
+    /// `retry_signup`, or rolled back, instead of leaking the canister it created.
+    ///
+    /// Repeating a request with the same `request_id` (by default, one per caller) does not
+    /// provision again: it returns the existing canister, reports the progress of a signup
+    /// that is still running, or resumes one that failed.
     #[update(name = "signup_new_user")]
-    async fn signup_new_user(user_args: CreateUserArgs) -> Result<UserCreateCanisterResult, String> {
-        let mut settings = UserCanisterSettings {
-            controllers: Some(vec![ic_cdk::api::caller(), ic_cdk::api::id()]),
-            compute_allocation: None,
-            memory_allocation: None,
-            freezing_threshold: None,
-        };
-        let mut controllers = settings.controllers.unwrap_or(vec![]);
-        if controllers.is_empty() {
-            controllers.push(ic_cdk::api::caller());
-            controllers.push(ic_cdk::api::id());
+    async fn signup_new_user(
+        user_args: CreateUserArgs,
+        request_id: Option<String>,
+    ) -> Result<UserCreateCanisterResult, Error> {
+        user_args.user.validate()?;
+        let request_key = (api::caller(), request_id.unwrap_or_default());
+        let existing = SIGNUP_REQUESTS
+            .with(|requests| requests.borrow().get(&request_key).copied())
+            .and_then(|signup_id| SIGNUPS.with(|signups| signups.borrow().get(&signup_id).cloned()));
+        if let Some(signup_id) = replayed_signup(existing.as_ref(), is_signup_running)? {
+            return advance_signup(signup_id).await;
         }
-        settings.controllers = Some(controllers.clone());
-        let args = UserCreateCanisterArgs {
-            cycles: 100_000_000_000,
-            settings,
-        };
-        let create_canister_result = create_canister_call(args).await?;
 
-        install_user(&create_canister_result.canister_id, get_wasm_content(USER_CANISTER_WASM_MODULE_URL.to_string()).await?).await?;
-        match api::call::call(create_canister_result.canister_id, "create_user", (user_args,)).await {
-            Ok(x) => x,
-            Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ))
-            }
+        // Keeps concurrent first requests of one caller from provisioning twice.
+        let _lock = Lock::caller()?;
+        let now = api::time();
+        let signup_id = NEXT_SIGNUP_ID.with(|next| {
+            let id = *next.borrow();
+            *next.borrow_mut() += 1;
+            id
+        });
+        let signup = Signup {
+            id: signup_id,
+            owner: api::caller(),
+            user: user_args.user,
+            stage: SignupStage::Started,
+            canister_id: None,
+            created_at: None,
+            wasm_version: None,
+            attempts: 0,
+            last_error: None,
+            started_at: now,
+            updated_at: now,
         };
+        SIGNUPS.with(|signups| signups.borrow_mut().insert(signup_id, signup));
+        SIGNUP_REQUESTS.with(|requests| requests.borrow_mut().insert(request_key, signup_id));
+        advance_signup(signup_id).await
+    }
 
-        USER_CANISTERS.with(|canisters| canisters.borrow_mut().push(create_canister_result.canister_id.clone()));
+    /// Resume a failed signup from the step that failed.
+    #[update(name = "retry_signup")]
+    async fn retry_signup(signup_id: u64) -> Result<UserCreateCanisterResult, Error> {
+        authorize_signup_access(&get_signup_record(signup_id)?)?;
+        advance_signup(signup_id).await
+    }
 
-        Ok(create_canister_result)
+    /// Give up on a signup: its canister, if any, is emptied and kept for the next signup.
+    #[update(name = "rollback_signup")]
+    async fn rollback_signup(signup_id: u64) -> Result<(), Error> {
+        let signup = get_signup_record(signup_id)?;
+        authorize_signup_access(&signup)?;
+        if is_signup_running(signup_id) {
+            return Err(Error::SignupInProgress {
+                signup_id,
+                stage: signup.stage,
+            });
+        }
+        roll_back(signup_id).await
     }
 
-    #[ic_cdk::update]
-    async fn get_wasm_content(url: String) -> Result<Vec<u8>, String> {
-        let request_headers = vec![];
-        
-        let request = CanisterHttpRequestArgument {
-            url: url.to_string(),
-            method: HttpMethod::GET,
-            body: None,               //optional for request
-            max_response_bytes: None, //optional for request
-            transform: None,          //optional for request
-            headers: request_headers,
+    #[query(name = "get_signup")]
+    fn get_signup(signup_id: u64) -> Result<Signup, Error> {
+        let signup = get_signup_record(signup_id)?;
+        authorize_signup_access(&signup)?;
+        Ok(signup)
+    }
+
+    /// Signups that hold a canister but never got registered.
+    #[query(name = "list_orphaned_signups", guard = "is_controller")]
+    fn list_orphaned_signups() -> Vec<Signup> {
+        SIGNUPS.with(|signups| {
+            signups
+                .borrow()
+                .values()
+                .filter(|signup| signup.is_orphaned())
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn get_signup_record(signup_id: u64) -> Result<Signup, Error> {
+        SIGNUPS
+            .with(|signups| signups.borrow().get(&signup_id).cloned())
+            .ok_or_else(|| Error::NotFound(format!("Signup {}", signup_id)))
+    }
+
+    fn authorize_signup_access(signup: &Signup) -> Result<(), Error> {
+        let caller = api::caller();
+        if caller == signup.owner || api::is_controller(&caller) {
+            Ok(())
+        } else {
+            Err(Error::NotAuthorized)
+        }
+    }
+
+    fn update_signup(signup_id: u64, f: impl FnOnce(&mut Signup)) {
+        SIGNUPS.with(|signups| {
+            if let Some(signup) = signups.borrow_mut().get_mut(&signup_id) {
+                f(signup);
+                signup.updated_at = api::time();
+            }
+        });
+    }
+
+    /// The signup a repeated request continues, or `None` if it has to start a new one.
+    fn replayed_signup(existing: Option<&Signup>, is_running: impl Fn(u64) -> bool) -> Result<Option<u64>, Error> {
+        match existing {
+            None => Ok(None),
+            Some(signup) if signup.stage == SignupStage::RolledBack => Ok(None),
+            Some(signup) if signup.stage != SignupStage::Registered && is_running(signup.id) => {
+                Err(Error::SignupInProgress {
+                    signup_id: signup.id,
+                    stage: signup.stage,
+                })
+            }
+            Some(signup) => Ok(Some(signup.id)),
+        }
+    }
+
+    fn is_signup_running(signup_id: u64) -> bool {
+        RUNNING_SIGNUPS.with(|running| running.borrow().contains(&signup_id))
+    }
+
+    /// Marks a signup as running until dropped.
+    struct RunningSignup(u64);
+
+    impl RunningSignup {
+        fn start(signup_id: u64) -> Result<Self, Error> {
+            if let Some(signup) = SIGNUPS.with(|signups| signups.borrow().get(&signup_id).cloned()) {
+                if !RUNNING_SIGNUPS.with(|running| running.borrow_mut().insert(signup_id)) {
+                    return Err(Error::SignupInProgress {
+                        signup_id,
+                        stage: signup.stage,
+                    });
+                }
+            }
+            Ok(Self(signup_id))
+        }
+    }
+
+    impl Drop for RunningSignup {
+        fn drop(&mut self) {
+            RUNNING_SIGNUPS.with(|running| running.borrow_mut().remove(&self.0));
+        }
+    }
+
+    /// What `advance_signup` does next for a signup.
+    #[derive(Debug, PartialEq)]
+    enum SignupStep {
+        Done(Principal),
+        RolledBack,
+        Acquire,
+        Install(Principal, InstallMode),
+        CreateUser(Principal),
+        Register(Principal),
+        // A stage past `Started` without a canister; only possible if the record is corrupt.
+        MissingCanister(SignupStage),
+    }
+
+    fn next_step(signup: &Signup) -> SignupStep {
+        match (signup.stage, signup.canister_id) {
+            (SignupStage::Registered, Some(canister_id)) => SignupStep::Done(canister_id),
+            (SignupStage::RolledBack, _) => SignupStep::RolledBack,
+            (SignupStage::Started, _) => SignupStep::Acquire,
+            (SignupStage::Created, Some(canister_id)) => {
+                // A previous attempt may have failed after the code was installed.
+                let mode = if signup.attempts == 0 { InstallMode::Install } else { InstallMode::Reinstall };
+                SignupStep::Install(canister_id, mode)
+            }
+            (SignupStage::Installed, Some(canister_id)) => SignupStep::CreateUser(canister_id),
+            (SignupStage::Initialized, Some(canister_id)) => SignupStep::Register(canister_id),
+            (stage, None) => SignupStep::MissingCanister(stage),
+        }
+    }
+
+    /// Count a failed step against the signup and tell whether it should be rolled back.
+    /// `Busy` only means another message holds the canister, so it is not counted.
+    fn record_failure(signup: &mut Signup, error: &Error) -> bool {
+        if matches!(error, Error::Busy(_)) {
+            return false;
+        }
+        signup.attempts += 1;
+        signup.last_error = Some(error.clone());
+        signup.attempts >= MAX_SIGNUP_ATTEMPTS
+    }
+
+    /// Run the remaining signup steps, recording each completed one.
+    async fn advance_signup(signup_id: u64) -> Result<UserCreateCanisterResult, Error> {
+        let _running = RunningSignup::start(signup_id)?;
+        loop {
+            let signup = get_signup_record(signup_id)?;
+            let step = match next_step(&signup) {
+                SignupStep::Done(canister_id) => {
+                    return Ok(UserCreateCanisterResult { canister_id });
+                }
+                SignupStep::RolledBack => {
+                    return Err(Error::InvalidArgument(format!("Signup {} was rolled back", signup_id)));
+                }
+                SignupStep::Acquire => acquire_user_canister(signup.owner).await.map(|canister_id| {
+                    update_signup(signup_id, |signup| signup.created_at = Some(api::time()));
+                    (SignupStage::Created, Some(canister_id))
+                }),
+                SignupStep::Install(canister_id, mode) => match Lock::canister(canister_id) {
+                    Ok(_lock) => match fetch_user_wasm().await {
+                        Ok(wasm_module) => {
+                            let wasm_version = registered_version(&Sha256::digest(&wasm_module));
+                            install_user(&canister_id, mode, wasm_module, signup.owner).await.map(|_| {
+                                update_signup(signup_id, |signup| signup.wasm_version = wasm_version);
+                                (SignupStage::Installed, Some(canister_id))
+                            })
+                        }
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                },
+                SignupStep::CreateUser(canister_id) => match Lock::canister(canister_id) {
+                    Ok(_lock) => call_create_user(canister_id, CreateUserArgs { user: signup.user.clone() })
+                        .await
+                        .map(|_| (SignupStage::Initialized, Some(canister_id))),
+                    Err(e) => Err(e),
+                },
+                SignupStep::Register(canister_id) => {
+                    let entry = UserCanister {
+                        canister_id,
+                        owner: signup.owner,
+                        created_at: signup.created_at.unwrap_or(signup.started_at),
+                        wasm_version: signup.wasm_version.clone(),
+                        code_version: None,
+                        status: None,
+                        cycles: None,
+                        checked_at: None,
+                        self_custodied_at: None,
+                    };
+                    USER_CANISTERS.with(|canisters| canisters.borrow_mut().insert(canister_id, entry));
+                    Ok((SignupStage::Registered, Some(canister_id)))
+                }
+                SignupStep::MissingCanister(stage) => Err(Error::InvalidArgument(format!(
+                    "Signup {} is {:?} but has no canister",
+                    signup_id, stage
+                ))),
+            };
+
+            match step {
+                Ok((stage, canister_id)) => update_signup(signup_id, |signup| signup.complete_step(stage, canister_id)),
+                Err(error @ Error::Busy(_)) => return Err(error),
+                Err(error) => {
+                    let mut exhausted = false;
+                    update_signup(signup_id, |signup| exhausted = record_failure(signup, &error));
+                    if exhausted {
+                        // Best effort: if this fails too the signup stays listed as orphaned.
+                        let _ = roll_back(signup_id).await;
+                    }
+                    return Err(Error::SignupFailed {
+                        signup_id,
+                        error: Box::new(error),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Reuse a canister left over by a rolled back signup, or create a new one.
+    async fn acquire_user_canister(owner: Principal) -> Result<Principal, Error> {
+        if let Some(canister_id) = SPARE_CANISTERS.with(|spare| spare.borrow_mut().pop()) {
+            if let Err(error) = set_controllers(canister_id, vec![owner, api::id()]).await {
+                SPARE_CANISTERS.with(|spare| spare.borrow_mut().push(canister_id));
+                return Err(error);
+            }
+            return Ok(canister_id);
+        }
+
+        let args = UserCreateCanisterArgs {
+            cycles: USER_CANISTER_CYCLES,
+            settings: UserCanisterSettings {
+                controllers: Some(vec![owner, api::id()]),
+                compute_allocation: None,
+                memory_allocation: None,
+                freezing_threshold: None,
+            },
         };
+        Ok(create_canister_call(args).await?.canister_id)
+    }
 
-        match http_request(request).await {
-            Ok((response,)) => {
-                // Return the content of the response body as Vec<u8>
-                Ok(response.body)
+    /// Empty the canister of a signup that did not complete and keep it, with its cycles,
+    /// for the next signup.
+    async fn roll_back(signup_id: u64) -> Result<(), Error> {
+        let signup = get_signup_record(signup_id)?;
+        match signup.stage {
+            SignupStage::Registered => {
+                return Err(Error::InvalidArgument(format!("Signup {} is already registered", signup_id)));
             }
-            Err((r, m)) => {
-                let message =
-                    format!("The http_request resulted into error. RejectionCode: {r:?}, Error: {m}");
-    
-                //Return the error as a string and end the method
-                Err(message)
+            SignupStage::RolledBack => return Ok(()),
+            _ => {}
+        }
+
+        if let Some(canister_id) = signup.canister_id {
+            let _lock = Lock::canister(canister_id)?;
+            if let Err(error) = release_canister(canister_id).await {
+                update_signup(signup_id, |signup| signup.last_error = Some(error.clone()));
+                return Err(error);
+            }
+        }
+        update_signup(signup_id, |signup| {
+            signup.stage = SignupStage::RolledBack;
+            signup.canister_id = None;
+        });
+        Ok(())
+    }
+
+    /// Empty a user canister, take it back from its owner and keep it for the next signup.
+    async fn release_canister(canister_id: Principal) -> Result<(), Error> {
+        uninstall_code(CanisterIdRecord { canister_id })
+            .await
+            .map_err(|(code, msg)| Error::management_call("uninstall_code", code, msg))?;
+        set_controllers(canister_id, vec![api::id()]).await?;
+        SPARE_CANISTERS.with(|spare| spare.borrow_mut().push(canister_id));
+        Ok(())
+    }
+
+    async fn set_controllers(canister_id: Principal, controllers: Vec<Principal>) -> Result<(), Error> {
+        update_settings(UpdateSettingsArgument {
+            canister_id,
+            settings: CanisterSettings {
+                controllers: Some(controllers),
+                compute_allocation: None,
+                memory_allocation: None,
+                freezing_threshold: None,
+            },
+        })
+        .await
+        .map_err(|(code, msg)| Error::management_call("update_settings", code, msg))
+    }
+
+    /// Download a wasm module from `url`, retrying according to the download config.
+    #[update(name = "get_wasm_content")]
+    async fn get_wasm_content(url: String) -> Result<Vec<u8>, Error> {
+        ensure_controller()?;
+        let config = WASM_DOWNLOAD_CONFIG.with(|config| config.borrow().clone());
+        fetch_wasm(&[url], &config).await
+    }
+
+    /// Fetch the user canister module from the configured source. Gzipped modules are
+    /// returned as they are since the management canister installs them directly.
+    async fn fetch_user_wasm() -> Result<Vec<u8>, Error> {
+        let module = match USER_WASM_SOURCE.with(|source| source.borrow().clone()) {
+            UserWasmSource::Http => {
+                let config = WASM_DOWNLOAD_CONFIG.with(|config| config.borrow().clone());
+                fetch_wasm(&config.urls, &config).await?
+            }
+            UserWasmSource::AssetCanister(asset) => fetch_asset(&asset).await?,
+        };
+        validate_user_wasm(&module)?;
+        Ok(module)
+    }
+
+    /// Read an asset chunk by chunk from a certified asset canister.
+    async fn fetch_asset(asset: &AssetSource) -> Result<Vec<u8>, Error> {
+        #[derive(CandidType)]
+        struct GetArg {
+            key: String,
+            accept_encodings: Vec<String>,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct EncodedAsset {
+            #[serde(with = "serde_bytes")]
+            content: Vec<u8>,
+            content_type: String,
+            content_encoding: String,
+            sha256: Option<serde_bytes::ByteBuf>,
+            total_length: Nat,
+        }
+
+        #[derive(CandidType)]
+        struct GetChunkArg {
+            key: String,
+            content_encoding: String,
+            index: Nat,
+            sha256: Option<Vec<u8>>,
+        }
+
+        #[derive(CandidType, Deserialize)]
+        struct GetChunkResponse {
+            #[serde(with = "serde_bytes")]
+            content: Vec<u8>,
+        }
+
+        let get_arg = GetArg {
+            key: asset.key.clone(),
+            accept_encodings: vec!["identity".to_string()],
+        };
+        let (first,): (EncodedAsset,) = match api::call::call(asset.canister_id, "get", (get_arg,)).await {
+            Ok(x) => x,
+            Err((code, msg)) => return Err(Error::canister_call(asset.canister_id, "get", code, msg)),
+        };
+
+        let total_length = first
+            .total_length
+            .0
+            .to_usize()
+            .ok_or_else(|| Error::DownloadFailed(vec![format!("Asset {} is too large", asset.key)]))?;
+        let sha256 = first.sha256.map(|hash| hash.into_vec());
+        let mut content = first.content;
+        let mut index = 1u64;
+        while content.len() < total_length {
+            let get_chunk_arg = GetChunkArg {
+                key: asset.key.clone(),
+                content_encoding: first.content_encoding.clone(),
+                index: Nat::from(index),
+                sha256: sha256.clone(),
+            };
+            let (chunk,): (GetChunkResponse,) = match api::call::call(asset.canister_id, "get_chunk", (get_chunk_arg,)).await {
+                Ok(x) => x,
+                Err((code, msg)) => return Err(Error::canister_call(asset.canister_id, "get_chunk", code, msg)),
+            };
+            if chunk.content.is_empty() {
+                return Err(Error::DownloadFailed(vec![format!(
+                    "Asset {} ended after {} of {} bytes",
+                    asset.key,
+                    content.len(),
+                    total_length
+                )]));
+            }
+            content.extend(chunk.content);
+            index += 1;
+        }
+
+        if let Some(expected) = sha256 {
+            if Sha256::digest(&content).as_slice() != expected.as_slice() {
+                return Err(Error::DownloadFailed(vec![format!("Asset {} does not match its sha256", asset.key)]));
+            }
+        }
+        Ok(content)
+    }
+
+    async fn fetch_wasm(urls: &[String], config: &WasmDownloadConfig) -> Result<Vec<u8>, Error> {
+        if urls.is_empty() {
+            return Err(Error::InvalidArgument("No wasm download URL is configured".to_string()));
+        }
+        let max_response_bytes = config.expected_size + RESPONSE_HEADERS_ALLOWANCE;
+        if max_response_bytes > MAX_HTTP_RESPONSE_BYTES {
+            return Err(Error::InvalidArgument(format!(
+                "Expected wasm size {} exceeds the HTTP outcall response limit of {} bytes",
+                config.expected_size,
+                MAX_HTTP_RESPONSE_BYTES - RESPONSE_HEADERS_ALLOWANCE
+            )));
+        }
+
+        let mut errors = vec![];
+        for url in download_attempts(urls, config.max_attempts) {
+            let request = CanisterHttpRequestArgument {
+                url: url.clone(),
+                method: HttpMethod::GET,
+                body: None,
+                max_response_bytes: Some(max_response_bytes),
+                transform: Some(TransformContext::from_name("transform_wasm_response".to_string(), vec![])),
+                headers: vec![],
+            };
+            let cycles = http_request_cycles(&request, config.subnet_size);
+            ensure_cycles(cycles)?;
+
+            match http_request_with_cycles(request, cycles).await {
+                Ok((response,)) if response.status == 200 => return Ok(response.body),
+                Ok((response,)) => errors.push(format!("{url}: unexpected status {}", response.status)),
+                Err((r, m)) => errors.push(format!(
+                    "{url}: the http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
+                )),
             }
         }
+        Err(Error::DownloadFailed(errors))
+    }
+
+    /// URLs to try in order: the configured ones in turn, `max_attempts` times in total.
+    fn download_attempts(urls: &[String], max_attempts: u32) -> impl Iterator<Item = &String> {
+        urls.iter().cycle().take(max_attempts.max(1) as usize)
+    }
+
+    /// Cycles charged for an HTTP outcall on a subnet with `subnet_size` nodes, see
+    /// https://internetcomputer.org/docs/current/developer-docs/gas-cost#special-features
+    fn http_request_cycles(request: &CanisterHttpRequestArgument, subnet_size: u32) -> u128 {
+        let n = subnet_size as u128;
+        let request_bytes = candid::encode_one(request).map_or(0, |bytes| bytes.len()) as u128;
+        let response_bytes = request.max_response_bytes.unwrap_or(MAX_HTTP_RESPONSE_BYTES) as u128;
+        (3_000_000 + 60_000 * n) * n + 400 * n * request_bytes + 800 * n * response_bytes
+    }
+
+    /// Drop everything replicas may disagree on so the response can go through consensus.
+    #[query(name = "transform_wasm_response")]
+    fn transform_wasm_response(args: TransformArgs) -> HttpResponse {
+        let body = if args.response.status == 200 {
+            args.response.body
+        } else {
+            vec![]
+        };
+        HttpResponse {
+            status: args.response.status,
+            headers: vec![],
+            body,
+        }
+    }
+
+    #[update(name = "set_wasm_download_config", guard = "is_controller")]
+    fn set_wasm_download_config(config: WasmDownloadConfig) {
+        WASM_DOWNLOAD_CONFIG.with(|current| *current.borrow_mut() = config);
+    }
+
+    #[query(name = "get_wasm_download_config")]
+    fn get_wasm_download_config() -> WasmDownloadConfig {
+        WASM_DOWNLOAD_CONFIG.with(|config| config.borrow().clone())
+    }
+
+    #[update(name = "set_user_wasm_source", guard = "is_controller")]
+    fn set_user_wasm_source(source: UserWasmSource) {
+        USER_WASM_SOURCE.with(|current| *current.borrow_mut() = source);
+    }
+
+    #[query(name = "get_user_wasm_source")]
+    fn get_user_wasm_source() -> UserWasmSource {
+        USER_WASM_SOURCE.with(|source| source.borrow().clone())
     }
 
-    async fn create_canister_call(args: UserCreateCanisterArgs<u128>) -> Result<UserCreateCanisterResult, String> {
+    /// SNS governance canister that new user canisters accept profile writes from.
+    #[update(name = "set_sns_governance", guard = "is_controller")]
+    fn set_sns_governance(governance: Option<Principal>) {
+        SNS_GOVERNANCE.with(|current| *current.borrow_mut() = governance);
+    }
+
+    #[query(name = "get_sns_governance")]
+    fn get_sns_governance() -> Option<Principal> {
+        SNS_GOVERNANCE.with(|governance| *governance.borrow())
+    }
+
+    async fn create_canister_call(args: UserCreateCanisterArgs<u128>) -> Result<UserCreateCanisterResult, Error> {
         #[derive(CandidType)]
         struct CreateCanisterArgument {
             settings: Option<UserCanisterSettings>,
@@ -456,6 +1724,7 @@ mod user {
             settings: Some(args.settings),
         };
 
+        ensure_cycles(args.cycles)?;
         let (create_result,): (UserCreateCanisterResult,) = match api::call::call_with_payment128(
             Principal::management_canister(),
             "create_canister",
@@ -464,22 +1733,40 @@ mod user {
         )
         .await {
             Ok(r) => r,
-            Err((code, msg)) => return Err(format!("Error while creating a canister: {}: {}", code as u8, msg)),
+            Err((code, msg)) => return Err(Error::management_call("create_canister", code, msg)),
         };
 
         Ok(create_result)
     }
 
-    async fn install_user(canister_id: &Principal, wasm_module: Vec<u8>) -> Result<(), String> {
-        // Install Wasm
-        #[derive(CandidType, Deserialize)]
-        enum InstallMode {
-            #[serde(rename = "install")]
-            Install,
-            #[serde(rename = "reinstall")]
-            Reinstall,
-            #[serde(rename = "upgrade")]
-            Upgrade,
+    /// How `install_code` sends a module to the management canister.
+    #[derive(Debug, PartialEq)]
+    enum InstallPlan {
+        Single,
+        Chunked {
+            // Byte ranges of the module uploaded with `upload_chunk`, in order.
+            chunks: Vec<Range<usize>>,
+            wasm_module_hash: Vec<u8>,
+        },
+    }
+
+    fn plan_install(wasm_module: &[u8], arg: &[u8]) -> InstallPlan {
+        if wasm_module.len() + arg.len() <= MAX_SINGLE_INSTALL_SIZE {
+            return InstallPlan::Single;
+        }
+        let chunks = (0..wasm_module.len())
+            .step_by(WASM_CHUNK_SIZE)
+            .map(|start| start..wasm_module.len().min(start + WASM_CHUNK_SIZE))
+            .collect();
+        InstallPlan::Chunked {
+            chunks,
+            wasm_module_hash: Sha256::digest(wasm_module).to_vec(),
+        }
+    }
+
+    async fn install_code(canister_id: &Principal, mode: InstallMode, wasm_module: Vec<u8>, arg: Vec<u8>) -> Result<(), Error> {
+        if let InstallPlan::Chunked { chunks, wasm_module_hash } = plan_install(&wasm_module, &arg) {
+            return install_chunked_code(canister_id, mode, &wasm_module, chunks, wasm_module_hash, arg).await;
         }
 
         #[derive(CandidType, Deserialize)]
@@ -492,13 +1779,13 @@ mod user {
         }
 
         let install_config = CanisterInstall {
-            mode: InstallMode::Install,
+            mode,
             canister_id: *canister_id,
-            wasm_module: wasm_module.clone(),
-            arg: b" ".to_vec(),
+            wasm_module,
+            arg,
         };
 
-        match api::call::call(
+        match api::call::call::<_, ()>(
             Principal::management_canister(),
             "install_code",
             (install_config,),
@@ -506,91 +1793,1544 @@ mod user {
         .await
         {
             Ok(x) => x,
-            Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ))
-            }
+            Err((code, msg)) => return Err(Error::management_call("install_code", code, msg)),
+        };
+
+        Ok(())
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct ChunkHash {
+        #[serde(with = "serde_bytes")]
+        hash: Vec<u8>,
+    }
+
+    async fn clear_chunk_store(canister_id: &Principal) -> Result<(), Error> {
+        #[derive(CandidType)]
+        struct ClearChunkStoreArgument {
+            canister_id: Principal,
+        }
+
+        match api::call::call::<_, ()>(
+            Principal::management_canister(),
+            "clear_chunk_store",
+            (ClearChunkStoreArgument { canister_id: *canister_id },),
+        )
+        .await
+        {
+            Ok(x) => x,
+            Err((code, msg)) => return Err(Error::management_call("clear_chunk_store", code, msg)),
         };
 
-        #[derive(Default, CandidType, Deserialize, Clone, Debug)]
-        struct User {
-            name: String,
-            age: u64,
-            email: String
+        Ok(())
+    }
+
+    /// Upload the `chunks` of `wasm_module` to the chunk store of `canister_id` and install it from
+    /// there. Used for modules that do not fit into a single `install_code` message.
+    async fn install_chunked_code(
+        canister_id: &Principal,
+        mode: InstallMode,
+        wasm_module: &[u8],
+        chunks: Vec<Range<usize>>,
+        wasm_module_hash: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), Error> {
+        #[derive(CandidType)]
+        struct UploadChunkArgument<'a> {
+            canister_id: Principal,
+            chunk: &'a [u8],
+        }
+
+        #[derive(CandidType)]
+        struct InstallChunkedCodeArgument {
+            mode: InstallMode,
+            target_canister: Principal,
+            store_canister: Option<Principal>,
+            chunk_hashes_list: Vec<ChunkHash>,
+            wasm_module_hash: Vec<u8>,
+            arg: Vec<u8>,
+        }
+
+        // Start from an empty store so leftovers of an interrupted install are not reused.
+        clear_chunk_store(canister_id).await?;
+
+        let mut chunk_hashes_list = Vec::with_capacity(chunks.len());
+        for range in chunks {
+            let upload_arg = UploadChunkArgument {
+                canister_id: *canister_id,
+                chunk: &wasm_module[range],
+            };
+            let (chunk_hash,): (ChunkHash,) = match api::call::call(
+                Principal::management_canister(),
+                "upload_chunk",
+                (upload_arg,),
+            )
+            .await
+            {
+                Ok(x) => x,
+                Err((code, msg)) => return Err(Error::management_call("upload_chunk", code, msg)),
+            };
+            chunk_hashes_list.push(chunk_hash);
         }
 
+        let install_arg = InstallChunkedCodeArgument {
+            mode,
+            target_canister: *canister_id,
+            store_canister: None,
+            chunk_hashes_list,
+            wasm_module_hash,
+            arg,
+        };
+        match api::call::call::<_, ()>(
+            Principal::management_canister(),
+            "install_chunked_code",
+            (install_arg,),
+        )
+        .await
+        {
+            Ok(x) => x,
+            Err((code, msg)) => return Err(Error::management_call("install_chunked_code", code, msg)),
+        };
+
+        clear_chunk_store(canister_id).await
+    }
+
+    /// Install the user canister code, making `owner` the only principal besides this canister
+    /// that can read the profile. The configured SNS governance canister may also write it.
+    async fn install_user(canister_id: &Principal, mode: InstallMode, wasm_module: Vec<u8>, owner: Principal) -> Result<(), Error> {
+        install_code(canister_id, mode, wasm_module, user_init_arg(owner)?).await
+    }
+
+    fn user_init_arg(owner: Principal) -> Result<Vec<u8>, Error> {
+        let governance = SNS_GOVERNANCE.with(|governance| *governance.borrow());
+        candid::encode_one(Some(UserCanisterInitArgs { owner, governance, storage_quota: None }))
+            .map_err(|e| Error::InvalidArgument(format!("Failed to encode user canister init args: {e}")))
+    }
+
+    /// The argument to install a user canister of `owner` with. Without init args carrying its
+    /// owner, the user canister would make the backend its owner and lock the user out. Upgrades
+    /// get them too, for canisters whose code kept nothing in stable memory.
+    fn install_arg(mode: InstallMode, arg: Vec<u8>, owner: Principal) -> Result<Vec<u8>, Error> {
+        match mode {
+            _ if arg.is_empty() => user_init_arg(owner),
+            InstallMode::Upgrade => Ok(arg),
+            InstallMode::Install | InstallMode::Reinstall => {
+                match candid::decode_one::<Option<UserCanisterInitArgs>>(&arg) {
+                    Ok(Some(init)) if init.owner == owner => Ok(arg),
+                    Ok(Some(init)) => Err(Error::InvalidArgument(format!(
+                        "Init args name {} as owner, but the canister belongs to {}",
+                        init.owner, owner
+                    ))),
+                    Ok(None) | Err(_) => Err(Error::InvalidArgument(
+                        "Init args of a user canister must carry its owner".to_string(),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Call `create_user` on a user canister and surface the errors it reports.
+    async fn call_create_user(canister_id: Principal, user_args: CreateUserArgs) -> Result<(), Error> {
         #[derive(CandidType, Deserialize)]
-        struct CreateUserArgs {
-            user: User
+        struct CreateUserResult {
+            user_id: Principal,
+        }
+
+        let (result,): (Result<CreateUserResult, UserError>,) = match api::call::call(canister_id, "create_user", (user_args,)).await {
+            Ok(x) => x,
+            Err((code, msg)) => return Err(Error::canister_call(canister_id, "create_user", code, msg)),
+        };
+        result.map(|_| ()).map_err(|e| e.into_error(canister_id))
+    }
+
+    pub(crate) fn registered_user_canisters() -> Vec<Principal> {
+        USER_CANISTERS.with(|canisters| canisters.borrow().keys().copied().collect())
+    }
+
+    /// Registered user canisters the backend still controls.
+    pub(crate) fn managed_user_canisters() -> Vec<Principal> {
+        USER_CANISTERS.with(|canisters| {
+            canisters
+                .borrow()
+                .values()
+                .filter(|canister| canister.self_custodied_at.is_none())
+                .map(|canister| canister.canister_id)
+                .collect()
+        })
+    }
+
+    /// Owner of `canister_id` if it is a registered user canister the backend still controls.
+    pub(crate) fn managed_owner(canister_id: Principal) -> Result<Principal, Error> {
+        managed_user_canister(canister_id).map(|canister| canister.owner)
+    }
+
+    /// The registry entry of `canister_id`, unless its owner has taken over custody.
+    fn managed_user_canister(canister_id: Principal) -> Result<UserCanister, Error> {
+        let canister = USER_CANISTERS
+            .with(|canisters| canisters.borrow().get(&canister_id).cloned())
+            .ok_or_else(|| Error::NotFound(format!("User canister {}", canister_id)))?;
+        if canister.self_custodied_at.is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "User canister {} is self-custodied and no longer managed by the backend",
+                canister_id
+            )));
         }
+        Ok(canister)
+    }
 
-        let create_user_arg = CreateUserArgs {
-            user: User {
-                name: "John".to_string(),
-                age: 30,
-                email: "dragon99steel@gmail.com".to_string()
+    /// Check that `module` is a wasm module or a gzipped one and return the raw wasm.
+    fn decode_wasm_module(module: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+        let wasm = if module.starts_with(GZIP_MAGIC) {
+            let decoder = gzip::Decoder::new(module).map_err(|e| format!("Invalid gzip header: {e}"))?;
+            let mut wasm = vec![];
+            decoder
+                .take(MAX_DECOMPRESSED_WASM_SIZE + 1)
+                .read_to_end(&mut wasm)
+                .map_err(|e| format!("Invalid gzip stream: {e}"))?;
+            if wasm.len() as u64 > MAX_DECOMPRESSED_WASM_SIZE {
+                return Err(format!("Decompressed wasm exceeds {MAX_DECOMPRESSED_WASM_SIZE} bytes"));
             }
+            Cow::Owned(wasm)
+        } else {
+            Cow::Borrowed(module)
         };
+        if !wasm.starts_with(WASM_MAGIC) || wasm.get(4..8) != Some(WASM_VERSION) {
+            return Err("Module is not a wasm module (bad magic or version)".to_string());
+        }
+        Ok(wasm)
+    }
 
-        match api::call::call(*canister_id, "create_user", (create_user_arg,)).await {
-            Ok(x) => x,
-            Err((code, msg)) => {
-                return Err(format!(
-                    "An error happened during the call: {}: {}",
-                    code as u8, msg
-                ))
+    /// Return the content of the `candid:service` custom section of `wasm`, public or private.
+    fn candid_service_section(wasm: &[u8]) -> Result<Option<&str>, String> {
+        metadata_section(wasm, "candid:service")
+    }
+
+    /// Return the content of the `icp:public` or `icp:private` metadata section `name` of `wasm`.
+    fn metadata_section<'a>(wasm: &'a [u8], name: &str) -> Result<Option<&'a str>, String> {
+        fn read_leb128(bytes: &[u8], offset: &mut usize) -> Result<usize, String> {
+            let mut value = 0usize;
+            for shift in (0..35).step_by(7) {
+                let byte = *bytes.get(*offset).ok_or("Truncated wasm module")?;
+                *offset += 1;
+                value |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            Err("Invalid LEB128 integer in wasm module".to_string())
+        }
+
+        let mut offset = WASM_MAGIC.len() + WASM_VERSION.len();
+        while offset < wasm.len() {
+            let section_id = wasm[offset];
+            offset += 1;
+            let size = read_leb128(wasm, &mut offset)?;
+            let section = wasm
+                .get(offset..offset + size)
+                .ok_or("Truncated wasm section")?;
+            offset += size;
+            if section_id != 0 {
+                continue;
+            }
+
+            let mut name_offset = 0;
+            let name_len = read_leb128(section, &mut name_offset)?;
+            let section_name = section
+                .get(name_offset..name_offset + name_len)
+                .ok_or("Truncated wasm custom section name")?;
+            let metadata_name = section_name
+                .strip_prefix(b"icp:public ")
+                .or_else(|| section_name.strip_prefix(b"icp:private "));
+            if metadata_name == Some(name.as_bytes()) {
+                let content = &section[name_offset + name_len..];
+                return std::str::from_utf8(content)
+                    .map(Some)
+                    .map_err(|_| format!("{} metadata is not valid UTF-8", name));
             }
+        }
+        Ok(None)
+    }
+
+    /// Check that `module` implements the service described by `user_canister.did`.
+    fn validate_user_wasm(module: &[u8]) -> Result<(), Error> {
+        let wasm = decode_wasm_module(module).map_err(Error::InvalidWasm)?;
+        let service = candid_service_section(&wasm)
+            .map_err(Error::InvalidWasm)?
+            .ok_or_else(|| Error::InvalidWasm("Module has no candid:service metadata, cannot check its interface".to_string()))?;
+        service_compatible(CandidSource::Text(service), CandidSource::Text(USER_CANISTER_DID))
+            .map_err(|e| Error::InvalidWasm(format!("Module is not compatible with user_canister.did: {e}")))
+    }
+
+    /// Parse the `user_canister:version` metadata written by the user canister build.
+    fn parse_version_metadata(metadata: &str) -> Result<UserCanisterVersion, String> {
+        let fields: BTreeMap<&str, &str> = metadata
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .map(|value| value.to_string())
+                .ok_or_else(|| format!("user_canister:version metadata has no {}", name))
         };
+        Ok(UserCanisterVersion {
+            version: field("version")?,
+            git_commit: field("git_commit")?,
+            schema_version: field("schema_version")?
+                .parse()
+                .map_err(|_| "user_canister:version metadata has an invalid schema_version".to_string())?,
+        })
+    }
 
+    fn wasm_info(version: String, module: &[u8]) -> Result<UserWasmInfo, Error> {
+        let wasm = decode_wasm_module(module).map_err(Error::InvalidWasm)?;
+        let code_version = metadata_section(&wasm, VERSION_METADATA)
+            .and_then(|metadata| metadata.map(parse_version_metadata).transpose())
+            .map_err(Error::InvalidWasm)?;
+        Ok(UserWasmInfo {
+            version,
+            code_version,
+            gzipped: module.starts_with(GZIP_MAGIC),
+            sha256: Sha256::digest(module).to_vec(),
+            wasm_sha256: Sha256::digest(&wasm).to_vec(),
+            size: module.len() as u64,
+        })
+    }
+
+    /// Store a user canister module (`.wasm` or `.wasm.gz`) under `version` so it can be installed later.
+    #[update(name = "register_user_wasm")]
+    fn register_user_wasm(args: RegisterUserWasmArgs) -> Result<UserWasmInfo, Error> {
+        ensure_controller()?;
+        if args.version.is_empty() {
+            return Err(Error::InvalidArgument("Wasm version must not be empty".to_string()));
+        }
+        validate_user_wasm(&args.wasm_module)?;
+        let info = wasm_info(args.version.clone(), &args.wasm_module)?;
+        USER_WASM_VERSIONS.with(|versions| {
+            versions.borrow_mut().insert(args.version, RegisteredWasm {
+                info: info.clone(),
+                module: args.wasm_module,
+            })
+        });
+        Ok(info)
+    }
+
+    #[query(name = "list_user_wasm_versions")]
+    fn list_user_wasm_versions() -> Vec<UserWasmInfo> {
+        USER_WASM_VERSIONS.with(|versions| {
+            versions
+                .borrow()
+                .values()
+                .map(|registered| registered.info.clone())
+                .collect()
+        })
+    }
+
+    /// Install, reinstall or upgrade a single registered user canister with a registered wasm version.
+    #[update(name = "install_user_canister")]
+    async fn install_user_canister(args: InstallUserCanisterArgs) -> Result<(), Error> {
+        ensure_controller()?;
+        let entry = managed_user_canister(args.canister_id)?;
+        let arg = install_arg(args.mode, args.arg, entry.owner)?;
+        let (wasm_module, code_version) = USER_WASM_VERSIONS
+            .with(|versions| {
+                versions
+                    .borrow()
+                    .get(&args.version)
+                    .map(|registered| (registered.module.clone(), registered.info.code_version.clone()))
+            })
+            .ok_or_else(|| Error::NotFound(format!("Wasm version {}", args.version)))?;
+        let _lock = Lock::canister(args.canister_id)?;
+
+        if let (InstallMode::Upgrade, Some(new)) = (args.mode, &code_version) {
+            // Canisters built before `version()` existed cannot tell, so only refuse known downgrades.
+            if let Ok(running) = query_version(args.canister_id).await {
+                if new.schema_version < running.schema_version {
+                    return Err(Error::InvalidArgument(format!(
+                        "Wasm version {} has schema version {}, older than schema version {} of the running code",
+                        args.version, new.schema_version, running.schema_version
+                    )));
+                }
+            }
+        }
+
+        install_code(&args.canister_id, args.mode, wasm_module, arg).await?;
+        let code_version = query_version(args.canister_id).await.ok().or(code_version);
+        USER_CANISTERS.with(|canisters| {
+            if let Some(canister) = canisters.borrow_mut().get_mut(&args.canister_id) {
+                canister.wasm_version = Some(args.version);
+                canister.code_version = code_version;
+            }
+        });
         Ok(())
     }
 
+    pub(crate) async fn query_version(canister_id: Principal) -> Result<UserCanisterVersion, Error> {
+        let (version,) = api::call::call(canister_id, "version", ())
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "version", code, msg))?;
+        Ok(version)
+    }
+
+    pub(crate) async fn query_health(canister_id: Principal) -> Result<UserCanisterHealth, Error> {
+        let (health,) = api::call::call(canister_id, "health", ())
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "health", code, msg))?;
+        Ok(health)
+    }
+
+    /// The registered version whose stored module hashes to `module_hash`.
+    fn registered_version(module_hash: &[u8]) -> Option<String> {
+        USER_WASM_VERSIONS.with(|versions| {
+            versions
+                .borrow()
+                .values()
+                .find(|registered| registered.info.sha256 == module_hash)
+                .map(|registered| registered.info.version.clone())
+        })
+    }
+
+    /// Record the outcome of a `canister_status` call made by the fleet refresh.
+    pub(crate) fn record_status(
+        canister_id: Principal,
+        status: CanisterStatusType,
+        cycles: Nat,
+        module_hash: Option<&[u8]>,
+        code_version: Option<UserCanisterVersion>,
+        checked_at: u64,
+    ) {
+        let wasm_version = module_hash.and_then(registered_version);
+        USER_CANISTERS.with(|canisters| {
+            if let Some(canister) = canisters.borrow_mut().get_mut(&canister_id) {
+                if code_version.is_some() {
+                    canister.code_version = code_version;
+                }
+                canister.status = Some(status);
+                canister.cycles = Some(cycles);
+                canister.checked_at = Some(checked_at);
+                if wasm_version.is_some() {
+                    canister.wasm_version = wasm_version;
+                }
+            }
+        });
+    }
+
     #[query(name = "get_user_canisters")]
     fn get_user_canisters() -> Vec<Principal> {
-        USER_CANISTERS.with(|canisters| canisters.borrow().clone())
+        registered_user_canisters()
     }
 
+    /// Page through the registry in canister id order, starting after `cursor`.
+    #[query(name = "list_user_canisters")]
+    fn list_user_canisters(
+        cursor: Option<Principal>,
+        limit: u64,
+        filter: Option<UserCanisterFilter>,
+    ) -> UserCanisterPage {
+        let limit = limit.clamp(1, MAX_REGISTRY_PAGE_SIZE) as usize;
+        let filter = filter.unwrap_or_default();
+        let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+        USER_CANISTERS.with(|canisters| {
+            let canisters = canisters.borrow();
+            let mut matching = canisters
+                .range((start, Bound::Unbounded))
+                .map(|(_, canister)| canister)
+                .filter(|canister| filter.matches(canister));
+            let entries: Vec<UserCanister> = matching.by_ref().take(limit).cloned().collect();
+            let next_cursor = match matching.next() {
+                Some(_) => entries.last().map(|canister| canister.canister_id),
+                None => None,
+            };
+            UserCanisterPage { entries, next_cursor }
+        })
+    }
+
+    /// The profile stored in the caller's user canister.
     #[update(name = "who_am_i")]
-    async fn get_user_canister_by_id(user_canister_id: Principal) -> Result<User, String> {
-        let contains_target = USER_CANISTERS.with(|canisters| canisters.borrow().iter().any(|canister| canister == &user_canister_id));
-
-        if contains_target {
-            let call_result = api::call::call::<_, (Result<User, String>, )>(user_canister_id, "get_user", (),)
-                            .await
-                            .map_err(|e| format!("Error calling get_period_range_realized_volatility: {:?}", e))?;
-            call_result.0
-                    .map_err(|e| format!("Error calling get_period_range_realized_volatility: {:?}", e))
-        } else {
-            return Err(format!("User canister with id {} does not exist", user_canister_id));
+    async fn who_am_i() -> Result<User, Error> {
+        fetch_user(own_user_canister()?).await
+    }
+
+    async fn fetch_user(user_canister_id: Principal) -> Result<User, Error> {
+        let call_result = api::call::call::<_, (Result<User, String>, )>(user_canister_id, "get_user", (),)
+                        .await
+                        .map_err(|(code, msg)| Error::canister_call(user_canister_id, "get_user", code, msg))?;
+        call_result.0.map_err(Error::UserCanisterError)
+    }
+
+    /// Change some fields of the caller's profile.
+    #[update(name = "update_user")]
+    async fn update_user(args: UpdateUserArgs) -> Result<User, Error> {
+        validate_patch(args.name.as_deref(), args.age, args.email.as_deref()).map_err(Error::Validation)?;
+        let user_canister_id = own_user_canister()?;
+        managed_user_canister(user_canister_id)?;
+        let _lock = Lock::canister(user_canister_id)?;
+        let (result,): (Result<User, UserError>,) = api::call::call(user_canister_id, "update_user", (args,))
+            .await
+            .map_err(|(code, msg)| Error::canister_call(user_canister_id, "update_user", code, msg))?;
+        result.map_err(|e| e.into_error(user_canister_id))
+    }
+
+    /// Delete the caller's profile and stored documents. The user canister stays registered to the caller.
+    #[update(name = "delete_user")]
+    async fn delete_user() -> Result<(), Error> {
+        let user_canister_id = own_user_canister()?;
+        managed_user_canister(user_canister_id)?;
+        let _lock = Lock::canister(user_canister_id)?;
+        let (result,): (Result<(), UserError>,) = api::call::call(user_canister_id, "delete_user", ())
+            .await
+            .map_err(|(code, msg)| Error::canister_call(user_canister_id, "delete_user", code, msg))?;
+        result.map_err(|e| e.into_error(user_canister_id))?;
+        forget_signup_profiles(api::caller());
+        Ok(())
+    }
+
+    /// Drop the profiles kept in the signups of `owner`, including unfinished ones.
+    fn forget_signup_profiles(owner: Principal) {
+        SIGNUPS.with(|signups| {
+            for signup in signups.borrow_mut().values_mut().filter(|signup| signup.owner == owner) {
+                signup.user = User::default();
+            }
+        });
+    }
+
+    /// Change how many bytes of documents the owner of a user canister may store.
+    #[update(name = "set_user_storage_quota")]
+    async fn set_user_storage_quota(canister_id: Principal, quota: u64) -> Result<(), Error> {
+        ensure_controller()?;
+        managed_user_canister(canister_id)?;
+        let (result,): (Result<(), UserError>,) = api::call::call(canister_id, "set_storage_quota", (quota,))
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "set_storage_quota", code, msg))?;
+        result.map_err(|e| e.into_error(canister_id))
+    }
+
+    /// Move the profile and documents of a user canister into a fresh one running the current
+    /// user wasm, and register the new canister in place of the old one, which is then emptied
+    /// and kept for future signups.
+    #[update(name = "migrate_user_canister")]
+    async fn migrate_user_canister(canister_id: Principal) -> Result<UserCreateCanisterResult, Error> {
+        ensure_controller()?;
+        let entry = managed_user_canister(canister_id)?;
+        let _source_lock = Lock::canister(canister_id)?;
+
+        let target = acquire_user_canister(entry.owner).await?;
+        let _target_lock = Lock::canister(target)?;
+        let copied = async {
+            let wasm_module = fetch_user_wasm().await?;
+            let wasm_version = registered_version(&Sha256::digest(&wasm_module));
+            install_user(&target, InstallMode::Install, wasm_module, entry.owner).await?;
+            // The owner writes to the source directly; those writes would be lost with it.
+            set_read_only(canister_id, true).await?;
+            copy_user_data(canister_id, target).await?;
+            Ok(wasm_version)
+        };
+        let wasm_version = match copied.await {
+            Ok(wasm_version) => wasm_version,
+            Err(error) => {
+                // Best effort: the source canister is untouched and stays registered.
+                let _ = set_read_only(canister_id, false).await;
+                let _ = release_canister(target).await;
+                return Err(error);
+            }
+        };
+
+        move_registration(
+            canister_id,
+            UserCanister {
+                canister_id: target,
+                wasm_version,
+                code_version: None,
+                status: None,
+                cycles: None,
+                checked_at: None,
+                ..entry
+            },
+        );
+        super::events::record(super::events::EventKind::UserCanisterMigrated {
+            from: canister_id,
+            to: target,
+        });
+        // Best effort: the old canister no longer holds the user's only copy of the data.
+        let _ = release_canister(canister_id).await;
+        Ok(UserCreateCanisterResult { canister_id: target })
+    }
+
+    /// Register `target` in place of `source`. Signups that ended with `source` point to `target`
+    /// too, so replaying them does not hand out `source` once it is given to another user.
+    fn move_registration(source: Principal, target: UserCanister) {
+        SIGNUPS.with(|signups| {
+            for signup in signups.borrow_mut().values_mut() {
+                if signup.canister_id == Some(source) {
+                    signup.canister_id = Some(target.canister_id);
+                }
+            }
+        });
+        USER_CANISTERS.with(|canisters| {
+            let mut canisters = canisters.borrow_mut();
+            canisters.remove(&source);
+            canisters.insert(target.canister_id, target);
+        });
+    }
+
+    /// Hand full control of a user canister to its owner. The backend drops itself and SNS
+    /// governance from the canister's readers and writers, removes itself from the controllers
+    /// and stops upgrading, migrating, monitoring and writing to the canister; it stays in the
+    /// registry so the owner can still be looked up.
+    #[update(name = "graduate_user_canister")]
+    async fn graduate_user_canister(canister_id: Principal) -> Result<(), Error> {
+        let entry = managed_user_canister(canister_id)?;
+        if entry.owner != api::caller() {
+            return Err(Error::NotAuthorized);
+        }
+        let _lock = Lock::canister(canister_id)?;
+        let access = UserCanisterAccess {
+            owner: entry.owner,
+            backend: entry.owner,
+            governance: None,
+        };
+        let (result,): (Result<(), UserError>,) = api::call::call(canister_id, "set_access", (access,))
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "set_access", code, msg))?;
+        match result {
+            // Access was already dropped by an earlier graduation that failed afterwards.
+            Ok(()) | Err(UserError::NotAuthorized) => {}
+            Err(e) => return Err(e.into_error(canister_id)),
+        }
+        set_controllers(canister_id, vec![entry.owner]).await?;
+        USER_CANISTERS.with(|canisters| {
+            if let Some(canister) = canisters.borrow_mut().get_mut(&canister_id) {
+                canister.self_custodied_at = Some(api::time());
+            }
+        });
+        super::events::record(super::events::EventKind::UserCanisterGraduated {
+            canister: canister_id,
+            owner: entry.owner,
+        });
+        Ok(())
+    }
+
+    /// Make the user canister refuse or accept changes to the profile and documents again.
+    async fn set_read_only(canister_id: Principal, read_only: bool) -> Result<(), Error> {
+        let (result,): (Result<(), UserError>,) = api::call::call(canister_id, "set_read_only", (read_only,))
+            .await
+            .map_err(|(code, msg)| Error::canister_call(canister_id, "set_read_only", code, msg))?;
+        result.map_err(|e| e.into_error(canister_id))
+    }
+
+    /// Copy `export_data` pages of `source` into `target` until the last page.
+    async fn copy_user_data(source: Principal, target: Principal) -> Result<(), Error> {
+        let mut start_after = None;
+        loop {
+            let args = ExportArgs {
+                start_after,
+                max_bytes: None,
+            };
+            let (page,): (Result<UserData, UserError>,) = api::call::call(source, "export_data", (args,))
+                .await
+                .map_err(|(code, msg)| Error::canister_call(source, "export_data", code, msg))?;
+            let page = page.map_err(|e| e.into_error(source))?;
+            start_after = page.next.clone();
+
+            let (imported,): (Result<(), UserError>,) = api::call::call(target, "import_data", (page,))
+                .await
+                .map_err(|(code, msg)| Error::canister_call(target, "import_data", code, msg))?;
+            imported.map_err(|e| e.into_error(target))?;
+            if start_after.is_none() {
+                return Ok(());
+            }
         }
     }
 
+    /// The first user canister the caller signed up for.
+    fn own_user_canister() -> Result<Principal, Error> {
+        let caller = api::caller();
+        USER_CANISTERS
+            .with(|canisters| {
+                canisters
+                    .borrow()
+                    .values()
+                    .filter(|canister| canister.owner == caller)
+                    .min_by_key(|canister| canister.created_at)
+                    .map(|canister| canister.canister_id)
+            })
+            .ok_or_else(|| Error::NotFound(format!("User canister of {}", caller)))
+    }
+
+    /// Replace the profile in a user canister. The backend writes to every user canister it
+    /// manages, so only SNS governance and controllers may ask it to.
     #[update(name = "sns_update_user_canister")]
-    async fn sns_update_user_canister(user_canister_id: String, user_args: CreateUserArgs) -> Result<String, String> {
-        let user_canister = Principal::from_text(user_canister_id).expect("Failed to convert string to principal");
-        match api::call::call(user_canister, "create_user", (user_args,),)
-                            .await {
-                                Ok(x) => x,
-                                Err((code, msg)) => {
-                                    return Err(format!(
-                                        "An error happened during the call: {}: {}",
-                                        code as u8, msg
-                                    ))
-                                }
-                            };
+    async fn sns_update_user_canister(user_canister_id: String, user_args: CreateUserArgs) -> Result<String, Error> {
+        ensure_governance()?;
+        user_args.user.validate()?;
+        let user_canister = parse_principal(&user_canister_id)?;
+        managed_user_canister(user_canister)?;
+        let _lock = Lock::canister(user_canister)?;
+        call_create_user(user_canister, user_args).await?;
         Ok("User canister updated successfully".to_string())
     }
 
+    // SNS governance requires validators to answer with `variant { Ok: text; Err: text }`. The
+    // text returned on success is shown to voters as the rendering of the proposal. It reads the
+    // current profile, so it answers only the callers `sns_update_user_canister` accepts.
     #[update(name = "sns_update_user_canister_validate")]
-    async fn sns_update_user_canister_validate(user_canister_id: String, _user_args: CreateUserArgs) -> Result<String, String> {
-        let user_canister = Principal::from_text(user_canister_id).expect("Failed to convert string to principal");
-        let contains_target = USER_CANISTERS.with(|canisters| canisters.borrow().iter().any(|canister| canister == &user_canister));
-        if contains_target {
-            Ok("Passed SNS update user canister validate successfully".to_string())
+    async fn sns_update_user_canister_validate(user_canister_id: String, user_args: CreateUserArgs) -> Result<String, String> {
+        ensure_governance().map_err(|e| e.to_string())?;
+        user_args.user.validate().map_err(|e| e.to_string())?;
+        let user_canister = parse_principal(&user_canister_id).map_err(|e| e.to_string())?;
+        managed_user_canister(user_canister).map_err(|e| e.to_string())?;
+        let current = fetch_user(user_canister).await.map_err(|e| e.to_string())?;
+        Ok(render_user_diff(user_canister, &current, &user_args.user))
+    }
+
+    /// Describe the change from `old` to `new` field by field, for SNS voters. Proposals are
+    /// public, so emails are masked.
+    fn render_user_diff(user_canister: Principal, old: &User, new: &User) -> String {
+        let fields = [
+            ("name", old.name != new.name, format!("{:?}", old.name), format!("{:?}", new.name)),
+            ("age", old.age != new.age, old.age.to_string(), new.age.to_string()),
+            ("email", old.email != new.email, format!("{:?}", mask_email(&old.email)), format!("{:?}", mask_email(&new.email))),
+        ];
+        // A canister without a profile holds the default user.
+        let is_new = *old == User::default();
+        let mut lines = vec![if is_new {
+            format!("Create the profile in user canister {}:", user_canister)
         } else {
-            return Err(format!("User canister with id {} does not exist", user_canister));
+            format!("Update the profile in user canister {}:", user_canister)
+        }];
+        // Changes are told from the values themselves, as two emails can look the same masked.
+        for (field, changed, old, new) in fields {
+            lines.push(if is_new {
+                format!("{}: {}", field, new)
+            } else if changed {
+                format!("{}: {} → {}", field, old, new)
+            } else {
+                format!("{}: {} (unchanged)", field, new)
+            });
+        }
+        lines.join("\n")
+    }
+
+    /// Keep the first character of the local part and the domain, e.g. `d***@gmail.com`.
+    fn mask_email(email: &str) -> String {
+        let (local, domain) = match email.split_once('@') {
+            Some((local, domain)) => (local, format!("@{}", domain)),
+            None => (email, String::new()),
+        };
+        match local.chars().next() {
+            Some(first) => format!("{}***{}", first, domain),
+            None => domain,
+        }
+    }
+
+    fn ensure_governance() -> Result<(), Error> {
+        let caller = api::caller();
+        let governance = SNS_GOVERNANCE.with(|governance| *governance.borrow());
+        if governance == Some(caller) || api::is_controller(&caller) {
+            Ok(())
+        } else {
+            Err(Error::NotAuthorized)
+        }
+    }
+
+    fn parse_principal(text: &str) -> Result<Principal, Error> {
+        Principal::from_text(text).map_err(|e| Error::InvalidArgument(format!("Invalid principal {}: {}", text, e)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ic_cdk::api::management_canister::http_request::HttpHeader;
+        use std::io::Write;
+
+        const EMPTY_WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+        fn gzip(bytes: &[u8]) -> Vec<u8> {
+            let mut encoder = gzip::Encoder::new(vec![]).unwrap();
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().into_result().unwrap()
+        }
+
+        #[test]
+        fn decode_wasm_module_accepts_raw_and_gzipped_wasm() {
+            assert_eq!(decode_wasm_module(EMPTY_WASM).unwrap().as_ref(), EMPTY_WASM);
+            assert_eq!(decode_wasm_module(&gzip(EMPTY_WASM)).unwrap().as_ref(), EMPTY_WASM);
+        }
+
+        #[test]
+        fn decode_wasm_module_rejects_invalid_modules() {
+            assert!(decode_wasm_module(b"<html></html>").is_err());
+            assert!(decode_wasm_module(&gzip(b"<html></html>")).is_err());
+            assert!(decode_wasm_module(&[0x1f, 0x8b, 0, 0]).is_err());
+        }
+
+        fn wasm_with_service(service: &str) -> Vec<u8> {
+            wasm_with_section("icp:public candid:service", service)
+        }
+
+        fn wasm_with_section(name: &str, content: &str) -> Vec<u8> {
+            let name = name.as_bytes();
+            let mut section = vec![name.len() as u8];
+            section.extend_from_slice(name);
+            section.extend_from_slice(content.as_bytes());
+
+            let mut wasm = EMPTY_WASM.to_vec();
+            wasm.push(0);
+            let mut size = section.len();
+            loop {
+                let byte = (size & 0x7f) as u8;
+                size >>= 7;
+                if size == 0 {
+                    wasm.push(byte);
+                    break;
+                }
+                wasm.push(byte | 0x80);
+            }
+            wasm.extend(section);
+            wasm
+        }
+
+        #[test]
+        fn validate_user_wasm_accepts_the_user_canister_interface() {
+            assert!(validate_user_wasm(&wasm_with_service(USER_CANISTER_DID)).is_ok());
+            assert!(validate_user_wasm(&gzip(&wasm_with_service(USER_CANISTER_DID))).is_ok());
+        }
+
+        #[test]
+        fn validate_user_wasm_rejects_other_interfaces() {
+            assert!(validate_user_wasm(EMPTY_WASM).is_err());
+            let service = "service : { greet: (text) -> (text) query }";
+            assert!(validate_user_wasm(&wasm_with_service(service)).is_err());
+        }
+
+        #[test]
+        fn wasm_info_hashes_both_forms() {
+            let module = gzip(EMPTY_WASM);
+            let info = wasm_info("1.0.0".to_string(), &module).unwrap();
+            assert!(info.gzipped);
+            assert_eq!(info.sha256, Sha256::digest(&module).to_vec());
+            assert_eq!(info.wasm_sha256, Sha256::digest(EMPTY_WASM).to_vec());
+        }
+
+        #[test]
+        fn wasm_info_reads_version_metadata() {
+            let metadata = "version=0.2.0\ngit_commit=abc123\nschema_version=2\n";
+            let module = wasm_with_section("icp:public user_canister:version", metadata);
+            let info = wasm_info("0.2.0".to_string(), &module).unwrap();
+            assert_eq!(
+                info.code_version,
+                Some(UserCanisterVersion {
+                    version: "0.2.0".to_string(),
+                    git_commit: "abc123".to_string(),
+                    schema_version: 2,
+                })
+            );
+            assert!(wasm_info("0.1.0".to_string(), EMPTY_WASM).unwrap().code_version.is_none());
+            let broken = wasm_with_section("icp:public user_canister:version", "version=0.2.0\n");
+            assert!(wasm_info("0.2.0".to_string(), &broken).is_err());
+        }
+
+        #[test]
+        fn filter_matches_only_given_fields() {
+            let canister = UserCanister {
+                canister_id: Principal::management_canister(),
+                owner: Principal::anonymous(),
+                created_at: 0,
+                wasm_version: Some("1.0.0".to_string()),
+                code_version: None,
+                status: None,
+                cycles: None,
+                checked_at: None,
+                self_custodied_at: None,
+            };
+            assert!(UserCanisterFilter::default().matches(&canister));
+            let by_owner = UserCanisterFilter {
+                owner: Some(Principal::anonymous()),
+                ..Default::default()
+            };
+            assert!(by_owner.matches(&canister));
+            let by_version = UserCanisterFilter {
+                wasm_version: Some("2.0.0".to_string()),
+                ..Default::default()
+            };
+            assert!(!by_version.matches(&canister));
+            let by_status = UserCanisterFilter {
+                status: Some(CanisterStatusType::Running),
+                ..Default::default()
+            };
+            assert!(!by_status.matches(&canister));
+        }
+
+        fn wasm_request(max_response_bytes: Option<u64>) -> CanisterHttpRequestArgument {
+            CanisterHttpRequestArgument {
+                url: "https://example.com/user_canister.wasm".to_string(),
+                method: HttpMethod::GET,
+                body: None,
+                max_response_bytes,
+                transform: None,
+                headers: vec![],
+            }
+        }
+
+        #[test]
+        fn http_request_cycles_follow_the_fee_schedule() {
+            let request = wasm_request(Some(0));
+            let request_bytes = candid::encode_one(&request).unwrap().len() as u128;
+            assert_eq!(
+                http_request_cycles(&request, 13),
+                (3_000_000 + 60_000 * 13) * 13 + 400 * 13 * request_bytes
+            );
+            let base = http_request_cycles(&wasm_request(Some(0)), 34);
+            assert_eq!(http_request_cycles(&wasm_request(Some(1_000)), 34) - base, 800 * 34 * 1_000);
+            // Without a limit the full response size is charged.
+            let unbounded = wasm_request(None);
+            let request_bytes = candid::encode_one(&unbounded).unwrap().len() as u128;
+            assert_eq!(
+                http_request_cycles(&unbounded, 13),
+                (3_000_000 + 60_000 * 13) * 13 + 400 * 13 * request_bytes + 800 * 13 * MAX_HTTP_RESPONSE_BYTES as u128
+            );
+        }
+
+        fn transform(status: u64, body: &[u8]) -> HttpResponse {
+            transform_wasm_response(TransformArgs {
+                response: HttpResponse {
+                    status: status.into(),
+                    headers: vec![HttpHeader {
+                        name: "date".to_string(),
+                        value: "Mon, 19 Oct 2026 00:00:00 GMT".to_string(),
+                    }],
+                    body: body.to_vec(),
+                },
+                context: vec![],
+            })
+        }
+
+        #[test]
+        fn transform_keeps_only_status_and_successful_bodies() {
+            let ok = transform(200, b"\0asm");
+            assert_eq!(ok.status, 200u64);
+            assert!(ok.headers.is_empty());
+            assert_eq!(ok.body, b"\0asm");
+
+            let not_found = transform(404, b"<html>Not found</html>");
+            assert_eq!(not_found.status, 404u64);
+            assert!(not_found.headers.is_empty());
+            assert!(not_found.body.is_empty());
+        }
+
+        #[test]
+        fn download_attempts_rotate_through_mirrors() {
+            let urls = vec!["primary".to_string(), "mirror".to_string()];
+            let attempts: Vec<&String> = download_attempts(&urls, 3).collect();
+            assert_eq!(attempts, vec!["primary", "mirror", "primary"]);
+            assert_eq!(download_attempts(&urls, 1).count(), 1);
+            // At least one attempt is made even if max_attempts is zero.
+            assert_eq!(download_attempts(&urls, 0).count(), 1);
+        }
+
+        #[test]
+        fn large_modules_are_installed_in_chunks() {
+            let largest_single = vec![0u8; MAX_SINGLE_INSTALL_SIZE];
+            assert_eq!(plan_install(&largest_single, &[]), InstallPlan::Single);
+
+            let wasm_module = vec![7u8; 2 * WASM_CHUNK_SIZE + 10];
+            assert_eq!(
+                plan_install(&wasm_module, &[]),
+                InstallPlan::Chunked {
+                    chunks: vec![0..WASM_CHUNK_SIZE, WASM_CHUNK_SIZE..2 * WASM_CHUNK_SIZE, 2 * WASM_CHUNK_SIZE..wasm_module.len()],
+                    wasm_module_hash: Sha256::digest(&wasm_module).to_vec(),
+                }
+            );
+
+            // The init argument travels in the same message as the module.
+            assert_eq!(
+                plan_install(&largest_single, &[0]),
+                InstallPlan::Chunked {
+                    chunks: vec![0..WASM_CHUNK_SIZE, WASM_CHUNK_SIZE..MAX_SINGLE_INSTALL_SIZE],
+                    wasm_module_hash: Sha256::digest(&largest_single).to_vec(),
+                }
+            );
+        }
+
+        #[test]
+        fn renders_changed_fields_of_the_profile() {
+            let canister = Principal::management_canister();
+            let old = User {
+                name: "James".to_string(),
+                age: 28,
+                email: "dragon99steel@gmail.com".to_string(),
+            };
+            let new = User {
+                name: "James Fury".to_string(),
+                ..old.clone()
+            };
+            assert_eq!(
+                render_user_diff(canister, &old, &new),
+                "Update the profile in user canister aaaaa-aa:\n\
+                 name: \"James\" → \"James Fury\"\n\
+                 age: 28 (unchanged)\n\
+                 email: \"d***@gmail.com\" (unchanged)"
+            );
+            let moved = User {
+                email: "dragon1227@gmail.com".to_string(),
+                ..old.clone()
+            };
+            assert!(render_user_diff(canister, &old, &moved).ends_with("email: \"d***@gmail.com\" → \"d***@gmail.com\""));
+            assert!(render_user_diff(canister, &User::default(), &new)
+                .starts_with("Create the profile in user canister aaaaa-aa:\nname: \"James Fury\"\n"));
+            assert_eq!(mask_email("no-at-sign"), "n***");
+            assert_eq!(mask_email(""), "");
+        }
+
+        fn signup(stage: SignupStage, canister_id: Option<Principal>) -> Signup {
+            Signup {
+                id: 3,
+                owner: Principal::anonymous(),
+                user: User::default(),
+                stage,
+                canister_id,
+                created_at: None,
+                wasm_version: None,
+                attempts: 0,
+                last_error: None,
+                started_at: 0,
+                updated_at: 0,
+            }
+        }
+
+        #[test]
+        fn next_step_follows_the_stage() {
+            let canister = Principal::management_canister();
+            assert_eq!(next_step(&signup(SignupStage::Started, None)), SignupStep::Acquire);
+            assert_eq!(
+                next_step(&signup(SignupStage::Created, Some(canister))),
+                SignupStep::Install(canister, InstallMode::Install)
+            );
+            let retried = Signup {
+                attempts: 1,
+                ..signup(SignupStage::Created, Some(canister))
+            };
+            assert_eq!(next_step(&retried), SignupStep::Install(canister, InstallMode::Reinstall));
+            assert_eq!(next_step(&signup(SignupStage::Installed, Some(canister))), SignupStep::CreateUser(canister));
+            assert_eq!(next_step(&signup(SignupStage::Initialized, Some(canister))), SignupStep::Register(canister));
+            assert_eq!(next_step(&signup(SignupStage::Registered, Some(canister))), SignupStep::Done(canister));
+            assert_eq!(next_step(&signup(SignupStage::RolledBack, Some(canister))), SignupStep::RolledBack);
+            assert_eq!(
+                next_step(&signup(SignupStage::Installed, None)),
+                SignupStep::MissingCanister(SignupStage::Installed)
+            );
         }
+
+        #[test]
+        fn failures_roll_back_after_max_attempts() {
+            let mut failing = signup(SignupStage::Created, Some(Principal::management_canister()));
+            let error = Error::InvalidWasm("truncated".to_string());
+            for _ in 1..MAX_SIGNUP_ATTEMPTS {
+                assert!(!record_failure(&mut failing, &error));
+            }
+            assert!(record_failure(&mut failing, &error));
+            assert_eq!(failing.attempts, MAX_SIGNUP_ATTEMPTS);
+            assert!(failing.last_error.is_some());
+        }
+
+        #[test]
+        fn busy_canisters_are_not_counted_as_failures() {
+            let mut waiting = signup(SignupStage::Created, Some(Principal::management_canister()));
+            waiting.attempts = MAX_SIGNUP_ATTEMPTS - 1;
+            assert!(!record_failure(&mut waiting, &Error::Busy("canister".to_string())));
+            assert_eq!(waiting.attempts, MAX_SIGNUP_ATTEMPTS - 1);
+            assert!(waiting.last_error.is_none());
+        }
+
+        #[test]
+        fn repeated_requests_continue_their_signup() {
+            let canister = Principal::management_canister();
+            let idle = |_| false;
+            let running = |_| true;
+            assert_eq!(replayed_signup(None, idle).unwrap(), None);
+
+            let registered = signup(SignupStage::Registered, Some(canister));
+            assert_eq!(replayed_signup(Some(&registered), running).unwrap(), Some(3));
+
+            let installed = signup(SignupStage::Installed, Some(canister));
+            assert!(matches!(
+                replayed_signup(Some(&installed), running),
+                Err(Error::SignupInProgress {
+                    signup_id: 3,
+                    stage: SignupStage::Installed
+                })
+            ));
+            // A failed signup that is no longer running is resumed.
+            assert_eq!(replayed_signup(Some(&installed), idle).unwrap(), Some(3));
+
+            let rolled_back = signup(SignupStage::RolledBack, Some(canister));
+            assert_eq!(replayed_signup(Some(&rolled_back), idle).unwrap(), None);
+        }
+
+        #[test]
+        fn migrated_signups_replay_the_new_canister() {
+            let source = Principal::management_canister();
+            let target = Principal::anonymous();
+            SIGNUPS.with(|signups| signups.borrow_mut().insert(3, signup(SignupStage::Registered, Some(source))));
+            move_registration(
+                source,
+                UserCanister {
+                    canister_id: target,
+                    owner: Principal::anonymous(),
+                    created_at: 0,
+                    wasm_version: None,
+                    code_version: None,
+                    status: None,
+                    cycles: None,
+                    checked_at: None,
+                    self_custodied_at: None,
+                },
+            );
+
+            let replayed = SIGNUPS.with(|signups| signups.borrow().get(&3).cloned()).unwrap();
+            assert_eq!(replayed_signup(Some(&replayed), |_| false).unwrap(), Some(3));
+            assert_eq!(next_step(&replayed), SignupStep::Done(target));
+            assert_eq!(registered_user_canisters(), vec![target]);
+        }
+
+        #[test]
+        fn signups_do_not_keep_the_profile() {
+            let user = User {
+                name: "James".to_string(),
+                age: 28,
+                email: "dragon99steel@gmail.com".to_string(),
+            };
+            let canister = Some(Principal::management_canister());
+            let mut completed = Signup {
+                user: user.clone(),
+                ..signup(SignupStage::Installed, canister)
+            };
+            completed.complete_step(SignupStage::Initialized, canister);
+            assert_eq!(completed.user, user);
+            completed.complete_step(SignupStage::Registered, canister);
+            assert_eq!(completed.user, User::default());
+
+            let unfinished = Signup {
+                user,
+                ..signup(SignupStage::Created, canister)
+            };
+            SIGNUPS.with(|signups| signups.borrow_mut().insert(3, unfinished));
+            forget_signup_profiles(Principal::anonymous());
+            assert_eq!(SIGNUPS.with(|signups| signups.borrow()[&3].user.clone()), User::default());
+        }
+
+        #[test]
+        fn installs_carry_the_owner() {
+            let owner = Principal::anonymous();
+            let built = install_arg(InstallMode::Reinstall, vec![], owner).unwrap();
+            let init = candid::decode_one::<Option<UserCanisterInitArgs>>(&built).unwrap().unwrap();
+            assert_eq!(init.owner, owner);
+
+            let given = user_init_arg(owner).unwrap();
+            assert_eq!(install_arg(InstallMode::Install, given.clone(), owner).unwrap(), given);
+            let other = install_arg(InstallMode::Install, given, Principal::management_canister());
+            assert!(matches!(other, Err(Error::InvalidArgument(_))));
+            let unit = candid::encode_args(()).unwrap();
+            assert!(install_arg(InstallMode::Reinstall, unit.clone(), owner).is_err());
+            assert_eq!(install_arg(InstallMode::Upgrade, unit.clone(), owner).unwrap(), unit);
+            let upgrade = install_arg(InstallMode::Upgrade, vec![], owner).unwrap();
+            assert_eq!(candid::decode_one::<Option<UserCanisterInitArgs>>(&upgrade).unwrap().unwrap().owner, owner);
+        }
+
+        #[test]
+        fn state_survives_an_upgrade() {
+            let caller = Principal::anonymous();
+            SPARE_CANISTERS.with(|spare| spare.borrow_mut().push(Principal::management_canister()));
+            NEXT_SIGNUP_ID.with(|id| *id.borrow_mut() = 7);
+            SIGNUP_REQUESTS.with(|requests| requests.borrow_mut().insert((caller, "first".to_string()), 6));
+            SNS_GOVERNANCE.with(|governance| *governance.borrow_mut() = Some(caller));
+
+            let bytes = candid::encode_one(save_state()).unwrap();
+            SPARE_CANISTERS.with(|spare| spare.borrow_mut().clear());
+            NEXT_SIGNUP_ID.with(|id| *id.borrow_mut() = 0);
+            SIGNUP_REQUESTS.with(|requests| requests.borrow_mut().clear());
+            SNS_GOVERNANCE.with(|governance| *governance.borrow_mut() = None);
+            restore_state(candid::decode_one(&bytes).unwrap());
+
+            assert_eq!(SPARE_CANISTERS.with(|spare| spare.borrow().clone()), vec![Principal::management_canister()]);
+            assert_eq!(NEXT_SIGNUP_ID.with(|id| *id.borrow()), 7);
+            assert_eq!(
+                SIGNUP_REQUESTS.with(|requests| requests.borrow().get(&(caller, "first".to_string())).copied()),
+                Some(6)
+            );
+            assert_eq!(SNS_GOVERNANCE.with(|governance| *governance.borrow()), Some(caller));
+        }
+
+        #[test]
+        fn self_custodied_canisters_are_not_managed() {
+            let managed = Principal::management_canister();
+            let graduated = Principal::anonymous();
+            USER_CANISTERS.with(|canisters| {
+                let mut canisters = canisters.borrow_mut();
+                for (canister_id, self_custodied_at) in [(managed, None), (graduated, Some(1))] {
+                    canisters.insert(
+                        canister_id,
+                        UserCanister {
+                            canister_id,
+                            owner: Principal::anonymous(),
+                            created_at: 0,
+                            wasm_version: None,
+                            code_version: None,
+                            status: None,
+                            cycles: None,
+                            checked_at: None,
+                            self_custodied_at,
+                        },
+                    );
+                }
+            });
+            assert_eq!(managed_user_canisters(), vec![managed]);
+            assert_eq!(registered_user_canisters().len(), 2);
+            assert!(managed_user_canister(managed).is_ok());
+            assert!(matches!(managed_user_canister(graduated), Err(Error::InvalidArgument(_))));
+        }
+    }
+}
+
+mod fleet {
+    use futures::future::{join, join_all};
+    use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord, CanisterStatusType};
+    use ic_cdk::export::candid::{CandidType, Deserialize, Nat, Principal};
+    use ic_cdk::{api, query, update};
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::user::{query_health, query_version, UserCanisterHealth, UserCanisterVersion};
+    use super::{is_controller, Error};
+
+    /// Number of `canister_status` calls kept in flight at once while refreshing.
+    const STATUS_BATCH_SIZE: usize = 20;
+    /// User canisters holding fewer cycles than this are counted as low on cycles.
+    const LOW_CYCLES_THRESHOLD: u64 = 10_000_000_000;
+    const MAX_PAGE_SIZE: u64 = 100;
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct CanisterHealth {
+        status: CanisterStatusType,
+        cycles: Nat,
+        memory_size: Nat,
+        module_hash: Option<Vec<u8>>,
+        controllers: Vec<Principal>,
+        freezing_threshold: Nat,
+    }
+
+    #[derive(CandidType, Clone, Deserialize)]
+    struct FleetEntry {
+        canister_id: Principal,
+        checked_at: u64,
+        health: Result<CanisterHealth, Error>,
+        // Reported by the user canister itself; absent if it is not running or does not answer.
+        version: Option<UserCanisterVersion>,
+        user_health: Option<UserCanisterHealth>,
+    }
+
+    #[derive(CandidType, Default, Deserialize)]
+    struct FleetSummary {
+        total: u64,
+        running: u64,
+        stopping: u64,
+        stopped: u64,
+        low_cycles: u64,
+        unreachable: u64,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct FleetStatusPage {
+        entries: Vec<FleetEntry>,
+        total: u64,
+        summary: FleetSummary,
+        refreshed_at: Option<u64>,
+    }
+
+    thread_local! {
+        static FLEET_STATUS: RefCell<BTreeMap<Principal, FleetEntry>> = Default::default();
+        static LAST_REFRESH: RefCell<Option<u64>> = Default::default();
     }
-}
\ No newline at end of file
+
+    async fn fetch_health(canister_id: Principal) -> FleetEntry {
+        let checked_at = api::time();
+        let health = match canister_status(CanisterIdRecord { canister_id }).await {
+            Ok((status,)) => Ok(CanisterHealth {
+                status: status.status,
+                cycles: status.cycles,
+                memory_size: status.memory_size,
+                module_hash: status.module_hash,
+                controllers: status.settings.controllers,
+                freezing_threshold: status.settings.freezing_threshold,
+            }),
+            Err((code, msg)) => Err(Error::management_call("canister_status", code, msg)),
+        };
+        let (version, user_health) = match &health {
+            Ok(health) if health.status == CanisterStatusType::Running => {
+                let (version, user_health) = join(query_version(canister_id), query_health(canister_id)).await;
+                (version.ok(), user_health.ok())
+            }
+            _ => (None, None),
+        };
+        if let Ok(health) = &health {
+            super::user::record_status(
+                canister_id,
+                health.status,
+                health.cycles.clone(),
+                health.module_hash.as_deref(),
+                version.clone(),
+                checked_at,
+            );
+        }
+        FleetEntry {
+            canister_id,
+            checked_at,
+            health,
+            version,
+            user_health,
+        }
+    }
+
+    fn summarize(entries: &BTreeMap<Principal, FleetEntry>) -> FleetSummary {
+        let low_cycles = Nat::from(LOW_CYCLES_THRESHOLD);
+        let mut summary = FleetSummary {
+            total: entries.len() as u64,
+            ..Default::default()
+        };
+        for entry in entries.values() {
+            match &entry.health {
+                Ok(health) => {
+                    match health.status {
+                        CanisterStatusType::Running => summary.running += 1,
+                        CanisterStatusType::Stopping => summary.stopping += 1,
+                        CanisterStatusType::Stopped => summary.stopped += 1,
+                    }
+                    if health.cycles < low_cycles {
+                        summary.low_cycles += 1;
+                    }
+                }
+                Err(_) => summary.unreachable += 1,
+            }
+        }
+        summary
+    }
+
+    /// Collect `canister_status` for every registered user canister and cache the results.
+    #[update(name = "refresh_fleet_status", guard = "is_controller")]
+    async fn refresh_fleet_status() -> FleetSummary {
+        // Self-custodied canisters no longer answer `canister_status` to the backend.
+        let canister_ids = super::user::managed_user_canisters();
+        for batch in canister_ids.chunks(STATUS_BATCH_SIZE) {
+            let entries = join_all(batch.iter().map(|canister_id| fetch_health(*canister_id))).await;
+            FLEET_STATUS.with(|fleet| {
+                let mut fleet = fleet.borrow_mut();
+                for entry in entries {
+                    fleet.insert(entry.canister_id, entry);
+                }
+            });
+        }
+        LAST_REFRESH.with(|last| *last.borrow_mut() = Some(api::time()));
+        FLEET_STATUS.with(|fleet| {
+            let mut fleet = fleet.borrow_mut();
+            let managed: BTreeSet<Principal> = canister_ids.into_iter().collect();
+            fleet.retain(|canister_id, _| managed.contains(canister_id));
+            summarize(&fleet)
+        })
+    }
+
+    // Entries name the owners among the controllers and describe their data, so only
+    // controllers may read them.
+    #[query(name = "get_fleet_status", guard = "is_controller")]
+    fn get_fleet_status(offset: u64, limit: u64) -> FleetStatusPage {
+        let limit = limit.min(MAX_PAGE_SIZE) as usize;
+        FLEET_STATUS.with(|fleet| {
+            let fleet = fleet.borrow();
+            FleetStatusPage {
+                entries: fleet
+                    .values()
+                    .skip(offset as usize)
+                    .take(limit)
+                    .cloned()
+                    .collect(),
+                total: fleet.len() as u64,
+                summary: summarize(&fleet),
+                refreshed_at: LAST_REFRESH.with(|last| *last.borrow()),
+            }
+        })
+    }
+}
+
+mod top_up {
+    use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
+    use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+    use ic_cdk::{api, query, update};
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    use super::events::{self, EventKind};
+    use super::{ensure_cycles, is_controller, Error};
+
+    const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+    /// How many cycles the backend sends when a user canister asks for a top-up.
+    #[derive(CandidType, Clone, Deserialize)]
+    struct TopUpPolicy {
+        // Cycles sent per top-up; zero disables top-ups.
+        amount: u128,
+        min_interval_seconds: u64,
+        // Cycles a user can receive in total; unlimited when absent.
+        max_total: Option<u128>,
+    }
+
+    impl Default for TopUpPolicy {
+        fn default() -> Self {
+            TopUpPolicy {
+                amount: 50_000_000_000,
+                min_interval_seconds: 24 * 60 * 60,
+                max_total: Some(500_000_000_000),
+            }
+        }
+    }
+
+    #[derive(CandidType, Clone, Default, Deserialize)]
+    struct TopUpHistory {
+        last_at: Option<u64>,
+        total: u128,
+    }
+
+    impl TopUpPolicy {
+        /// Cycles the user may receive at `now`, given the top-ups they already received.
+        fn allowance(&self, history: &TopUpHistory, now: u64) -> Result<u128, Error> {
+            if self.amount == 0 {
+                return Err(Error::TopUpRefused("Top-ups are disabled for this user".to_string()));
+            }
+            if let Some(last_at) = history.last_at {
+                let next_at = last_at.saturating_add(self.min_interval_seconds.saturating_mul(NANOS_PER_SECOND));
+                if now < next_at {
+                    return Err(Error::TopUpRefused(format!("The next top-up is allowed at {}", next_at)));
+                }
+            }
+            let remaining = self.max_total.map_or(u128::MAX, |max| max.saturating_sub(history.total));
+            if remaining == 0 {
+                return Err(Error::TopUpRefused("The user received all the cycles their policy allows".to_string()));
+            }
+            Ok(self.amount.min(remaining))
+        }
+    }
+
+    thread_local! {
+        static DEFAULT_POLICY: RefCell<TopUpPolicy> = RefCell::new(TopUpPolicy::default());
+        // Policies of single users, overriding the default one.
+        static POLICIES: RefCell<BTreeMap<Principal, TopUpPolicy>> = Default::default();
+        static HISTORY: RefCell<BTreeMap<Principal, TopUpHistory>> = Default::default();
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct StableState {
+        default_policy: TopUpPolicy,
+        policies: BTreeMap<Principal, TopUpPolicy>,
+        history: BTreeMap<Principal, TopUpHistory>,
+    }
+
+    pub fn save_state() -> StableState {
+        StableState {
+            default_policy: DEFAULT_POLICY.with(|policy| policy.borrow().clone()),
+            policies: POLICIES.with(|policies| policies.borrow().clone()),
+            history: HISTORY.with(|history| history.borrow().clone()),
+        }
+    }
+
+    pub fn restore_state(state: StableState) {
+        DEFAULT_POLICY.with(|policy| policy.replace(state.default_policy));
+        POLICIES.with(|policies| policies.replace(state.policies));
+        HISTORY.with(|history| history.replace(state.history));
+    }
+
+    fn policy_of(owner: Principal) -> TopUpPolicy {
+        POLICIES
+            .with(|policies| policies.borrow().get(&owner).cloned())
+            .unwrap_or_else(|| DEFAULT_POLICY.with(|policy| policy.borrow().clone()))
+    }
+
+    /// Called by a user canister whose balance runs low. Sends it cycles according to the
+    /// top-up policy of its owner and returns how many were sent.
+    #[update(name = "request_top_up")]
+    async fn request_top_up() -> Result<u128, Error> {
+        let canister_id = api::caller();
+        let owner = super::user::managed_owner(canister_id).map_err(|error| match error {
+            Error::NotFound(_) => Error::NotAuthorized,
+            error => error,
+        })?;
+        let now = api::time();
+        let previous = HISTORY.with(|history| history.borrow().get(&owner).cloned().unwrap_or_default());
+        let amount = policy_of(owner).allowance(&previous, now)?;
+        ensure_cycles(amount)?;
+
+        // Recorded before the call so concurrent requests of the same user see this top-up.
+        let reserved = TopUpHistory {
+            last_at: Some(now),
+            total: previous.total.saturating_add(amount),
+        };
+        HISTORY.with(|history| history.borrow_mut().insert(owner, reserved));
+        if let Err((code, msg)) = deposit_cycles(CanisterIdRecord { canister_id }, amount).await {
+            HISTORY.with(|history| history.borrow_mut().insert(owner, previous));
+            return Err(Error::management_call("deposit_cycles", code, msg));
+        }
+        events::record(EventKind::UserCanisterToppedUp {
+            canister: canister_id,
+            cycles: amount,
+        });
+        Ok(amount)
+    }
+
+    #[update(name = "set_default_top_up_policy", guard = "is_controller")]
+    fn set_default_top_up_policy(policy: TopUpPolicy) {
+        DEFAULT_POLICY.with(|default| default.replace(policy));
+    }
+
+    /// Give `owner` its own policy, or make it use the default one again with `null`.
+    #[update(name = "set_top_up_policy", guard = "is_controller")]
+    fn set_top_up_policy(owner: Principal, policy: Option<TopUpPolicy>) {
+        POLICIES.with(|policies| match policy {
+            Some(policy) => policies.borrow_mut().insert(owner, policy),
+            None => policies.borrow_mut().remove(&owner),
+        });
+    }
+
+    /// The policy that applies to `owner`.
+    #[query(name = "get_top_up_policy")]
+    fn get_top_up_policy(owner: Principal) -> TopUpPolicy {
+        policy_of(owner)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn allowance_follows_interval_and_limit() {
+            let policy = TopUpPolicy {
+                amount: 100,
+                min_interval_seconds: 10,
+                max_total: Some(150),
+            };
+            let fresh = TopUpHistory::default();
+            assert_eq!(policy.allowance(&fresh, 0).unwrap(), 100);
+
+            let topped_up = TopUpHistory {
+                last_at: Some(0),
+                total: 100,
+            };
+            assert!(matches!(policy.allowance(&topped_up, 5 * NANOS_PER_SECOND), Err(Error::TopUpRefused(_))));
+            assert_eq!(policy.allowance(&topped_up, 10 * NANOS_PER_SECOND).unwrap(), 50);
+
+            let exhausted = TopUpHistory {
+                last_at: Some(0),
+                total: 150,
+            };
+            assert!(policy.allowance(&exhausted, u64::MAX).is_err());
+            let disabled = TopUpPolicy { amount: 0, ..policy };
+            assert!(disabled.allowance(&fresh, 0).is_err());
+        }
+    }
+}
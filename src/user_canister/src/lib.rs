@@ -1,8 +1,8 @@
-use ic_cdk::*;
-use ic_cdk::export::candid::{CandidType};
+use ic_cdk::export::candid::{CandidType, Decode, Encode};
 use ic_cdk::export::Principal;
 use serde::Deserialize;
 use std::cell::RefCell;
+use user_profile::{validate_patch, validate_user, FieldError};
 
 #[derive(Default, CandidType, Deserialize, Clone, Debug)]
 struct User {
@@ -11,8 +11,268 @@ struct User {
     email: String
 }
 
+// Bump when the layout of stored data changes, and add the step from the previous version to
+// `migrations::STEPS`.
+macro_rules! schema_version {
+    () => {
+        2
+    };
+}
+
+const SCHEMA_VERSION: u32 = schema_version!();
+
+/// Build information, also embedded in the `user_canister:version` custom section so it can be
+/// read from the module before installing it.
+const VERSION_METADATA: &str = concat!(
+    "version=",
+    env!("CARGO_PKG_VERSION"),
+    "\ngit_commit=",
+    env!("GIT_COMMIT"),
+    "\nschema_version=",
+    schema_version!(),
+    "\n"
+);
+
+const fn to_bytes<const N: usize>(text: &str) -> [u8; N] {
+    let bytes = text.as_bytes();
+    let mut array = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        array[i] = bytes[i];
+        i += 1;
+    }
+    array
+}
+
+#[used]
+#[cfg_attr(target_arch = "wasm32", link_section = "icp:public user_canister:version")]
+static VERSION_SECTION: [u8; VERSION_METADATA.len()] = to_bytes(VERSION_METADATA);
+
 thread_local! {
     static USER_STORE: RefCell<User> = RefCell::new(User::default());
+    static ACCESS: RefCell<Access> = RefCell::new(Access::default());
+    // Whether the owner published their name on the HTTP pages and through `get_user_name`.
+    static PUBLIC_PROFILE: RefCell<bool> = const { RefCell::new(false) };
+    // Set by the backend while it copies the data to another canister, so no write is lost.
+    static READ_ONLY: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Principals allowed to read and write the profile. Only `owner` and `backend` can read it.
+#[derive(CandidType, Deserialize, Clone)]
+struct Access {
+    owner: Principal,
+    // The canister that installed this one.
+    backend: Principal,
+    // SNS governance canister, allowed to write when set.
+    governance: Option<Principal>,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access {
+            owner: Principal::anonymous(),
+            backend: Principal::anonymous(),
+            governance: None,
+        }
+    }
+}
+
+impl Access {
+    fn can_read(&self, principal: &Principal) -> bool {
+        principal == &self.owner || principal == &self.backend
+    }
+
+    fn can_write(&self, principal: &Principal) -> bool {
+        self.can_read(principal) || self.governance.as_ref() == Some(principal)
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    owner: Principal,
+    governance: Option<Principal>,
+    // Bytes of documents the owner may store, see `storage::DEFAULT_QUOTA`.
+    storage_quota: Option<u64>,
+}
+
+/// Heap state carried across upgrades.
+#[derive(CandidType, Deserialize)]
+struct UpgradeState {
+    user: User,
+    access: Access,
+    storage_quota: u64,
+    // Absent in state saved before profiles could be published.
+    public_profile: Option<bool>,
+    // Absent in state saved before the backend could make the canister read-only.
+    read_only: Option<bool>,
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let backend = ic_cdk::caller();
+    if let Some(quota) = args.as_ref().and_then(|args| args.storage_quota) {
+        storage::set_quota(quota);
+    }
+    let access = match args {
+        Some(args) => Access {
+            owner: args.owner,
+            backend,
+            governance: args.governance,
+        },
+        None => Access {
+            owner: backend,
+            backend,
+            governance: None,
+        },
+    };
+    ACCESS.with(|store| store.replace(access));
+    http::certify_profile(&User::default());
+    cycles::schedule();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = UpgradeState {
+        user: USER_STORE.with(|store| store.borrow().clone()),
+        access: ACCESS.with(|access| access.borrow().clone()),
+        storage_quota: storage::quota(),
+        public_profile: Some(is_profile_public()),
+        read_only: Some(is_read_only()),
+    };
+    let bytes = Encode!(&state).expect("Failed to encode upgrade state");
+    storage::save_upgrade_state(bytes);
+    storage::save_schema_version(SCHEMA_VERSION);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // Checked before anything opens the memory manager, which writes its header on first use.
+    let (version, bytes) = if ic_cdk::api::stable::stable64_size() == 0 {
+        (0, Vec::new())
+    } else if storage::has_legacy_layout() {
+        (1, ic_cdk::api::stable::stable_bytes())
+    } else {
+        // Code of schema version 2 did not record its version.
+        (storage::load_schema_version().unwrap_or(2), storage::load_upgrade_state())
+    };
+    let upgrade = migrations::Upgrade {
+        caller: ic_cdk::caller(),
+        args: args.as_ref(),
+    };
+    // Nothing is written before the state is fully migrated, so trapping keeps the old code and data.
+    let bytes = migrations::migrate(version, bytes, &upgrade).unwrap_or_else(|error| ic_cdk::trap(&error));
+    let state = Decode!(&bytes, UpgradeState).unwrap_or_else(|error| {
+        ic_cdk::trap(&format!("Failed to decode upgrade state: {}", error))
+    });
+    PUBLIC_PROFILE.with(|public| public.replace(state.public_profile.unwrap_or(false)));
+    READ_ONLY.with(|read_only| read_only.replace(state.read_only.unwrap_or(false)));
+    set_user(state.user);
+    ACCESS.with(|store| store.replace(state.access));
+    storage::set_quota(state.storage_quota);
+    cycles::schedule();
+}
+
+/// Store the profile and certify the pages that show it.
+fn set_user(user: User) {
+    http::certify_profile(&public_view(&user, is_profile_public()));
+    USER_STORE.with(|store| store.replace(user));
+}
+
+/// What anyone may see of `user`: its name if the owner published the profile, nothing otherwise.
+fn public_view(user: &User, public: bool) -> User {
+    if public {
+        User {
+            name: user.name.clone(),
+            ..User::default()
+        }
+    } else {
+        User::default()
+    }
+}
+
+#[ic_cdk::query]
+fn is_profile_public() -> bool {
+    PUBLIC_PROFILE.with(|public| *public.borrow())
+}
+
+/// Publish the name on the HTTP pages and through `get_user_name`, or make it private again.
+#[ic_cdk::update]
+fn set_profile_public(public: bool) -> Result<(), UserError> {
+    ensure_owner()?;
+    ensure_writable()?;
+    PUBLIC_PROFILE.with(|current| current.replace(public));
+    USER_STORE.with(|store| http::certify_profile(&public_view(&store.borrow(), public)));
+    Ok(())
+}
+
+fn ensure_reader() -> Result<(), String> {
+    if ACCESS.with(|access| access.borrow().can_read(&ic_cdk::caller())) {
+        Ok(())
+    } else {
+        Err("Only the owner or the backend can read this user".to_string())
+    }
+}
+
+fn ensure_owner_or_backend() -> Result<(), UserError> {
+    if ACCESS.with(|access| access.borrow().can_read(&ic_cdk::caller())) {
+        Ok(())
+    } else {
+        Err(UserError::NotAuthorized)
+    }
+}
+
+fn ensure_owner() -> Result<(), UserError> {
+    if ACCESS.with(|access| access.borrow().owner == ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(UserError::NotAuthorized)
+    }
+}
+
+fn ensure_writer() -> Result<(), UserError> {
+    if ACCESS.with(|access| access.borrow().can_write(&ic_cdk::caller())) {
+        ensure_writable()
+    } else {
+        Err(UserError::NotAuthorized)
+    }
+}
+
+fn ensure_writable() -> Result<(), UserError> {
+    if is_read_only() {
+        Err(UserError::ReadOnly)
+    } else {
+        Ok(())
+    }
+}
+
+#[ic_cdk::query]
+fn is_read_only() -> bool {
+    READ_ONLY.with(|read_only| *read_only.borrow())
+}
+
+/// Refuse or accept changes to the profile and documents again. Only the backend may do this.
+#[ic_cdk::update]
+fn set_read_only(read_only: bool) -> Result<(), UserError> {
+    if ACCESS.with(|access| access.borrow().backend != ic_cdk::caller()) {
+        return Err(UserError::NotAuthorized);
+    }
+    READ_ONLY.with(|current| current.replace(read_only));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_access() -> Access {
+    ACCESS.with(|access| access.borrow().clone())
+}
+
+/// Replace the principals allowed to use this canister. Only the owner or the backend may do this.
+#[ic_cdk::update]
+fn set_access(access: Access) -> Result<(), UserError> {
+    if !ACCESS.with(|current| current.borrow().can_read(&ic_cdk::caller())) {
+        return Err(UserError::NotAuthorized);
+    }
+    ACCESS.with(|current| current.replace(access));
+    Ok(())
 }
 
 #[derive(CandidType, Deserialize)]
@@ -25,22 +285,1125 @@ struct CreateUserResult {
     user_id: Principal
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+enum UserError {
+    NotAuthorized,
+    NotFound,
+    Validation(Vec<FieldError>),
+    InvalidKey(String),
+    QuotaExceeded { quota: u64, required: u64 },
+    UnsupportedFormat(u32),
+    // The backend is copying the data to another canister.
+    ReadOnly,
+}
+
+/// Fields to change; absent fields keep their current value.
+#[derive(CandidType, Deserialize)]
+struct UpdateUserArgs {
+    name: Option<String>,
+    age: Option<u64>,
+    email: Option<String>,
+}
+
+impl User {
+    // `delete_user` and a fresh canister leave the default, empty profile.
+    fn is_empty(&self) -> bool {
+        self.name.is_empty() && self.email.is_empty()
+    }
+}
+
 #[ic_cdk::update]
-async fn create_user(args: CreateUserArgs) -> Result<CreateUserResult, String> {
+async fn create_user(args: CreateUserArgs) -> Result<CreateUserResult, UserError> {
+    ensure_writer()?;
     let user_id = ic_cdk::id();
     let user = args.user;
-    USER_STORE.with(|store| {
-        store.replace(user);
-    });
+    validate_user(&user.name, user.age, &user.email).map_err(UserError::Validation)?;
+    set_user(user);
     Ok(CreateUserResult { user_id })
 }
 
 #[ic_cdk::query]
 async fn get_user() -> Result<User, String> {
+    ensure_reader()?;
     Ok(USER_STORE.with(|store| store.borrow().clone()))
 }
 
 #[ic_cdk::query]
 async fn get_user_name() -> Result<String, String> {
+    if !is_profile_public() {
+        ensure_reader()?;
+    }
     Ok(USER_STORE.with(|store| store.borrow().name.clone()))
-}
\ No newline at end of file
+}
+
+#[ic_cdk::update]
+fn update_user(args: UpdateUserArgs) -> Result<User, UserError> {
+    ensure_writer()?;
+    validate_patch(args.name.as_deref(), args.age, args.email.as_deref()).map_err(UserError::Validation)?;
+    let mut user = USER_STORE.with(|store| store.borrow().clone());
+    if user.is_empty() {
+        return Err(UserError::NotFound);
+    }
+    if let Some(name) = args.name {
+        user.name = name;
+    }
+    if let Some(age) = args.age {
+        user.age = age;
+    }
+    if let Some(email) = args.email {
+        user.email = email;
+    }
+    set_user(user.clone());
+    Ok(user)
+}
+
+/// Delete the account: the profile and every stored document.
+#[ic_cdk::update]
+fn delete_user() -> Result<(), UserError> {
+    ensure_writer()?;
+    storage::clear();
+    set_user(User::default());
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListDocumentsArgs {
+    namespace: String,
+    prefix: Option<String>,
+    // Key of the last document of the previous page.
+    start_after: Option<String>,
+    limit: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DocumentInfo {
+    key: String,
+    size: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DocumentPage {
+    documents: Vec<DocumentInfo>,
+    // Pass as `start_after` to get the next page; absent on the last page.
+    next: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StorageUsage {
+    used: u64,
+    quota: u64,
+}
+
+#[ic_cdk::update]
+fn put_document(namespace: String, key: String, value: serde_bytes::ByteBuf) -> Result<(), UserError> {
+    ensure_owner()?;
+    ensure_writable()?;
+    storage::put(namespace, key, value.into_vec())
+}
+
+#[ic_cdk::query]
+fn get_document(namespace: String, key: String) -> Result<Option<serde_bytes::ByteBuf>, UserError> {
+    ensure_owner()?;
+    Ok(storage::get(namespace, key)?.map(serde_bytes::ByteBuf::from))
+}
+
+/// Returns whether a document was removed.
+#[ic_cdk::update]
+fn delete_document(namespace: String, key: String) -> Result<bool, UserError> {
+    ensure_owner()?;
+    ensure_writable()?;
+    storage::delete(namespace, key)
+}
+
+#[ic_cdk::query]
+fn list_documents(args: ListDocumentsArgs) -> Result<DocumentPage, UserError> {
+    ensure_owner()?;
+    let limit = args.limit.clamp(1, storage::MAX_PAGE_SIZE) as usize;
+    let prefix = args.prefix.unwrap_or_default();
+    let mut documents = storage::list(args.namespace, &prefix, args.start_after)?;
+    let entries: Vec<DocumentInfo> = documents
+        .by_ref()
+        .take(limit)
+        .map(|(key, size)| DocumentInfo { key, size })
+        .collect();
+    let next = match documents.next() {
+        Some(_) => entries.last().map(|document| document.key.clone()),
+        None => None,
+    };
+    Ok(DocumentPage { documents: entries, next })
+}
+
+#[ic_cdk::query]
+fn get_storage_usage() -> Result<StorageUsage, UserError> {
+    ensure_owner()?;
+    Ok(StorageUsage {
+        used: storage::used(),
+        quota: storage::quota(),
+    })
+}
+
+/// Change how many bytes of documents may be stored. Only the backend may do this.
+#[ic_cdk::update]
+fn set_storage_quota(quota: u64) -> Result<(), UserError> {
+    if ACCESS.with(|access| access.borrow().backend != ic_cdk::caller()) {
+        return Err(UserError::NotAuthorized);
+    }
+    storage::set_quota(quota);
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize)]
+struct VersionInfo {
+    version: String,
+    git_commit: String,
+    schema_version: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Health {
+    has_profile: bool,
+    documents: u64,
+    document_bytes: u64,
+    heap_memory_bytes: u64,
+    stable_memory_bytes: u64,
+    cycles: u128,
+    last_top_up: Option<cycles::TopUpAttempt>,
+}
+
+#[ic_cdk::query]
+fn version() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: env!("GIT_COMMIT").to_string(),
+        schema_version: SCHEMA_VERSION,
+    }
+}
+
+#[ic_cdk::query]
+fn health() -> Health {
+    #[cfg(target_arch = "wasm32")]
+    let heap_pages = core::arch::wasm32::memory_size(0) as u64;
+    #[cfg(not(target_arch = "wasm32"))]
+    let heap_pages = 0;
+    Health {
+        has_profile: USER_STORE.with(|store| !store.borrow().is_empty()),
+        documents: storage::count(),
+        document_bytes: storage::used(),
+        heap_memory_bytes: heap_pages * WASM_PAGE_SIZE,
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        cycles: ic_cdk::api::canister_balance128(),
+        last_top_up: cycles::last_top_up(),
+    }
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Version of the `UserData` layout, independent of the storage schema version.
+const EXPORT_FORMAT_VERSION: u32 = 1;
+/// Default and maximum size of the documents in one export page, below the reply size limit.
+const MAX_EXPORT_PAGE_BYTES: u64 = 1_500_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct DocumentId {
+    namespace: String,
+    key: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExportArgs {
+    // Last document of the previous page; absent for the first page.
+    start_after: Option<DocumentId>,
+    max_bytes: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ExportedDocument {
+    namespace: String,
+    key: String,
+    value: serde_bytes::ByteBuf,
+}
+
+/// One page of exported data. Only the first page carries the profile and quota.
+#[derive(CandidType, Deserialize)]
+struct UserData {
+    format_version: u32,
+    schema_version: u32,
+    profile: Option<User>,
+    storage_quota: Option<u64>,
+    public_profile: Option<bool>,
+    documents: Vec<ExportedDocument>,
+    // Pass as `start_after` to export the next page; absent on the last page.
+    next: Option<DocumentId>,
+}
+
+#[ic_cdk::query]
+fn export_data(args: ExportArgs) -> Result<UserData, UserError> {
+    ensure_owner_or_backend()?;
+    let first_page = args.start_after.is_none();
+    let max_bytes = args.max_bytes.unwrap_or(MAX_EXPORT_PAGE_BYTES).min(MAX_EXPORT_PAGE_BYTES);
+    let start_after = args.start_after.map(|id| (id.namespace, id.key));
+    let (documents, more) = storage::export(start_after, max_bytes)?;
+    let next = if more {
+        documents.last().map(|(namespace, key, _)| DocumentId {
+            namespace: namespace.clone(),
+            key: key.clone(),
+        })
+    } else {
+        None
+    };
+    Ok(UserData {
+        format_version: EXPORT_FORMAT_VERSION,
+        schema_version: SCHEMA_VERSION,
+        profile: first_page.then(|| USER_STORE.with(|store| store.borrow().clone())),
+        storage_quota: first_page.then(storage::quota),
+        public_profile: first_page.then(is_profile_public),
+        documents: documents
+            .into_iter()
+            .map(|(namespace, key, value)| ExportedDocument {
+                namespace,
+                key,
+                value: serde_bytes::ByteBuf::from(value),
+            })
+            .collect(),
+        next,
+    })
+}
+
+/// Load a page produced by `export_data`. The quota is only taken over when the backend imports.
+#[ic_cdk::update]
+fn import_data(data: UserData) -> Result<(), UserError> {
+    ensure_owner_or_backend()?;
+    ensure_writable()?;
+    if data.format_version != EXPORT_FORMAT_VERSION {
+        return Err(UserError::UnsupportedFormat(data.format_version));
+    }
+    if let Some(user) = &data.profile {
+        if !user.is_empty() {
+            validate_user(&user.name, user.age, &user.email).map_err(UserError::Validation)?;
+        }
+    }
+    let is_backend = ACCESS.with(|access| access.borrow().backend == ic_cdk::caller());
+    if let (Some(quota), true) = (data.storage_quota, is_backend) {
+        storage::set_quota(quota);
+    }
+    for document in data.documents {
+        storage::put(document.namespace, document.key, document.value.into_vec())?;
+    }
+    if let Some(public) = data.public_profile {
+        PUBLIC_PROFILE.with(|current| current.replace(public));
+    }
+    if let Some(user) = data.profile {
+        set_user(user);
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn http_request(request: http::HttpRequest) -> http::HttpResponse {
+    http::serve(&request)
+}
+
+/// Public profile pages, certified so boundary nodes can verify them.
+mod http {
+    use ic_cdk::export::candid::CandidType;
+    use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+    use serde::{Deserialize, Serialize};
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use super::User;
+
+    const LABEL_ASSETS: &[u8] = b"http_assets";
+    const HTML_PATHS: [&str; 2] = ["/", "/index.html"];
+    const JSON_PATH: &str = "/profile.json";
+
+    #[derive(CandidType, Deserialize)]
+    pub struct HttpRequest {
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: ByteBuf,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct HttpResponse {
+        status_code: u16,
+        headers: Vec<(String, String)>,
+        body: ByteBuf,
+    }
+
+    struct Page {
+        content_type: &'static str,
+        body: Vec<u8>,
+    }
+
+    thread_local! {
+        static PAGES: RefCell<HashMap<&'static str, Page>> = RefCell::new(HashMap::new());
+        static TREE: RefCell<RbTree<&'static str, Hash>> = const { RefCell::new(RbTree::new()) };
+    }
+
+    /// The parts of a profile anyone may see.
+    #[derive(Serialize)]
+    struct PublicProfile<'a> {
+        name: &'a str,
+    }
+
+    fn render_json(user: &User) -> Vec<u8> {
+        if user.is_empty() {
+            return b"null".to_vec();
+        }
+        serde_json::to_vec(&PublicProfile { name: &user.name }).expect("Failed to serialize profile")
+    }
+
+    fn render_html(user: &User) -> Vec<u8> {
+        let content = if user.is_empty() {
+            "<p>This user has no public profile.</p>".to_string()
+        } else {
+            format!("<h1>{}</h1>", escape_html(&user.name))
+        };
+        format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Profile</title></head><body>{}</body></html>",
+            content
+        )
+        .into_bytes()
+    }
+
+    fn escape_html(text: &str) -> String {
+        text.chars()
+            .map(|c| match c {
+                '&' => "&amp;".to_string(),
+                '<' => "&lt;".to_string(),
+                '>' => "&gt;".to_string(),
+                '"' => "&quot;".to_string(),
+                '\'' => "&#39;".to_string(),
+                c => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// Render the pages for `user` and publish their hashes as the canister's certified data.
+    pub fn certify_profile(user: &User) {
+        let root_hash = store_pages(user);
+        ic_cdk::api::set_certified_data(&labeled_hash(LABEL_ASSETS, &root_hash));
+    }
+
+    /// Render and store the pages for `user`, returning the root hash of their tree.
+    fn store_pages(user: &User) -> Hash {
+        let html = render_html(user);
+        let json = render_json(user);
+        let mut pages: Vec<(&'static str, Page)> = HTML_PATHS
+            .iter()
+            .map(|path| (*path, Page { content_type: "text/html; charset=utf-8", body: html.clone() }))
+            .collect();
+        pages.push((JSON_PATH, Page { content_type: "application/json", body: json }));
+
+        let root_hash = TREE.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            for (path, page) in &pages {
+                tree.insert(*path, Sha256::digest(&page.body).into());
+            }
+            tree.root_hash()
+        });
+        PAGES.with(|current| current.borrow_mut().extend(pages));
+        root_hash
+    }
+
+    /// The stored path and page for a request url, ignoring the query string.
+    fn find_page(url: &str) -> Option<(&'static str, &'static str, Vec<u8>)> {
+        let path = url.split('?').next().unwrap_or_default();
+        PAGES.with(|pages| {
+            pages
+                .borrow()
+                .get_key_value(path)
+                .map(|(path, page)| (*path, page.content_type, page.body.clone()))
+        })
+    }
+
+    pub fn serve(request: &HttpRequest) -> HttpResponse {
+        if request.method != "GET" {
+            return not_found();
+        }
+        match find_page(&request.url) {
+            Some((path, content_type, body)) => HttpResponse {
+                status_code: 200,
+                headers: vec![
+                    ("Content-Type".to_string(), content_type.to_string()),
+                    ("IC-Certificate".to_string(), certificate_header(path)),
+                ],
+                body: ByteBuf::from(body),
+            },
+            None => not_found(),
+        }
+    }
+
+    fn not_found() -> HttpResponse {
+        HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: ByteBuf::from(b"Not found".to_vec()),
+        }
+    }
+
+    fn certificate_header(path: &str) -> String {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let certificate = ic_cdk::api::data_certificate().unwrap_or_default();
+        let tree = TREE.with(|tree| {
+            let tree = tree.borrow();
+            let witness = labeled(LABEL_ASSETS, tree.witness(path.as_bytes()));
+            let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+            serializer.self_describe().expect("Failed to write CBOR tag");
+            witness.serialize(&mut serializer).expect("Failed to serialize witness");
+            serializer.into_inner()
+        });
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            STANDARD.encode(certificate),
+            STANDARD.encode(tree)
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn pages_show_only_public_fields() {
+            let user = User {
+                name: "<James>".to_string(),
+                age: 28,
+                email: "dragon99steel@gmail.com".to_string(),
+            };
+            let json = String::from_utf8(render_json(&user)).unwrap();
+            assert_eq!(json, r#"{"name":"<James>"}"#);
+            let html = String::from_utf8(render_html(&user)).unwrap();
+            assert!(html.contains("&lt;James&gt;"));
+            assert!(!html.contains("dragon99steel"));
+        }
+
+        #[test]
+        fn stores_a_page_per_certified_path() {
+            let empty_root = store_pages(&User::default());
+            let (path, _, body) = find_page("/profile.json?x=1").unwrap();
+            assert_eq!(path, JSON_PATH);
+            assert_eq!(body, b"null");
+            assert!(find_page("/missing").is_none());
+
+            let user = User {
+                name: "James Fury".to_string(),
+                age: 28,
+                email: "dragon99steel@gmail.com".to_string(),
+            };
+            assert_ne!(store_pages(&user), empty_root);
+        }
+    }
+}
+
+/// Owner documents, kept in stable memory so they survive upgrades without copying.
+mod storage {
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+    use ic_stable_structures::storable::Bound;
+    use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::ops::Bound as RangeBound;
+
+    use super::UserError;
+
+    type Memory = VirtualMemory<DefaultMemoryImpl>;
+    /// Namespace, key and value.
+    pub type Document = (String, String, Vec<u8>);
+
+    pub const DEFAULT_QUOTA: u64 = 10 * 1024 * 1024;
+    pub const MAX_PAGE_SIZE: u32 = 100;
+    const MAX_NAMESPACE_LENGTH: usize = 64;
+    const MAX_KEY_LENGTH: usize = 256;
+
+    const UPGRADE_STATE_MEMORY: MemoryId = MemoryId::new(0);
+    const DOCUMENTS_MEMORY: MemoryId = MemoryId::new(1);
+    const USAGE_MEMORY: MemoryId = MemoryId::new(2);
+    const SCHEMA_VERSION_MEMORY: MemoryId = MemoryId::new(3);
+
+    thread_local! {
+        static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+            RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+        static DOCUMENTS: RefCell<StableBTreeMap<DocumentKey, Vec<u8>, Memory>> = RefCell::new(
+            StableBTreeMap::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(DOCUMENTS_MEMORY))),
+        );
+
+        // Bytes of keys and values in `DOCUMENTS`.
+        static USED: RefCell<StableCell<u64, Memory>> = RefCell::new(
+            StableCell::init(MEMORY_MANAGER.with(|manager| manager.borrow().get(USAGE_MEMORY)), 0)
+                .expect("Failed to initialize storage usage"),
+        );
+
+        static QUOTA: RefCell<u64> = const { RefCell::new(DEFAULT_QUOTA) };
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct DocumentKey {
+        namespace: String,
+        key: String,
+    }
+
+    impl DocumentKey {
+        fn new(namespace: String, key: String) -> Result<Self, UserError> {
+            if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LENGTH {
+                return Err(UserError::InvalidKey(format!(
+                    "namespace must be 1 to {} bytes long",
+                    MAX_NAMESPACE_LENGTH
+                )));
+            }
+            if key.len() > MAX_KEY_LENGTH {
+                return Err(UserError::InvalidKey(format!("key must be at most {} bytes long", MAX_KEY_LENGTH)));
+            }
+            Ok(Self { namespace, key })
+        }
+
+        fn size(&self) -> u64 {
+            (self.namespace.len() + self.key.len()) as u64
+        }
+    }
+
+    // Namespace length, namespace, key.
+    impl Storable for DocumentKey {
+        fn to_bytes(&self) -> Cow<'_, [u8]> {
+            let mut bytes = Vec::with_capacity(1 + self.namespace.len() + self.key.len());
+            bytes.push(self.namespace.len() as u8);
+            bytes.extend_from_slice(self.namespace.as_bytes());
+            bytes.extend_from_slice(self.key.as_bytes());
+            Cow::Owned(bytes)
+        }
+
+        fn from_bytes(bytes: Cow<[u8]>) -> Self {
+            let split = 1 + bytes[0] as usize;
+            Self {
+                namespace: String::from_utf8(bytes[1..split].to_vec()).expect("Invalid document namespace"),
+                key: String::from_utf8(bytes[split..].to_vec()).expect("Invalid document key"),
+            }
+        }
+
+        const BOUND: Bound = Bound::Bounded {
+            max_size: (1 + MAX_NAMESPACE_LENGTH + MAX_KEY_LENGTH) as u32,
+            is_fixed_size: false,
+        };
+    }
+
+    pub fn put(namespace: String, key: String, value: Vec<u8>) -> Result<(), UserError> {
+        let key = DocumentKey::new(namespace, key)?;
+        let replaced = DOCUMENTS.with(|documents| documents.borrow().get(&key)).map_or(0, |old| key.size() + old.len() as u64);
+        let required = used() - replaced + key.size() + value.len() as u64;
+        let quota = quota();
+        if required > quota {
+            return Err(UserError::QuotaExceeded { quota, required });
+        }
+        DOCUMENTS.with(|documents| documents.borrow_mut().insert(key, value));
+        set_used(required);
+        Ok(())
+    }
+
+    pub fn get(namespace: String, key: String) -> Result<Option<Vec<u8>>, UserError> {
+        let key = DocumentKey::new(namespace, key)?;
+        Ok(DOCUMENTS.with(|documents| documents.borrow().get(&key)))
+    }
+
+    pub fn delete(namespace: String, key: String) -> Result<bool, UserError> {
+        let key = DocumentKey::new(namespace, key)?;
+        match DOCUMENTS.with(|documents| documents.borrow_mut().remove(&key)) {
+            Some(value) => {
+                set_used(used() - key.size() - value.len() as u64);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Remove every document.
+    pub fn clear() {
+        DOCUMENTS.with(|documents| documents.borrow_mut().clear_new());
+        set_used(0);
+    }
+
+    /// Keys and value sizes of the documents in `namespace` whose key starts with `prefix`,
+    /// in key order, after `start_after`.
+    pub fn list(
+        namespace: String,
+        prefix: &str,
+        start_after: Option<String>,
+    ) -> Result<impl Iterator<Item = (String, u64)>, UserError> {
+        let from = match &start_after {
+            Some(after) if after.as_str() > prefix => after.clone(),
+            _ => prefix.to_string(),
+        };
+        let start = DocumentKey::new(namespace.clone(), from)?;
+        let documents: Vec<(String, u64)> = DOCUMENTS.with(|documents| {
+            documents
+                .borrow()
+                .range(start..)
+                .skip_while(|(key, _)| Some(&key.key) == start_after.as_ref())
+                .take_while(|(key, _)| key.namespace == namespace && key.key.starts_with(prefix))
+                .take(MAX_PAGE_SIZE as usize + 1)
+                .map(|(key, value)| (key.key, value.len() as u64))
+                .collect()
+        });
+        Ok(documents.into_iter())
+    }
+
+    pub fn count() -> u64 {
+        DOCUMENTS.with(|documents| documents.borrow().len())
+    }
+
+    /// Documents after `start_after` in key order, up to `max_bytes` of values but at least one,
+    /// and whether more documents follow.
+    pub fn export(start_after: Option<(String, String)>, max_bytes: u64) -> Result<(Vec<Document>, bool), UserError> {
+        let start = match start_after {
+            Some((namespace, key)) => RangeBound::Excluded(DocumentKey::new(namespace, key)?),
+            None => RangeBound::Unbounded,
+        };
+        DOCUMENTS.with(|documents| {
+            let documents = documents.borrow();
+            let mut page = vec![];
+            let mut bytes = 0;
+            for (key, value) in documents.range((start, RangeBound::Unbounded)) {
+                if !page.is_empty() && bytes + value.len() as u64 > max_bytes {
+                    return Ok((page, true));
+                }
+                bytes += value.len() as u64;
+                page.push((key.namespace, key.key, value));
+            }
+            Ok((page, false))
+        })
+    }
+
+    pub fn used() -> u64 {
+        USED.with(|used| *used.borrow().get())
+    }
+
+    fn set_used(bytes: u64) {
+        USED.with(|used| used.borrow_mut().set(bytes).expect("Failed to record storage usage"));
+    }
+
+    pub fn quota() -> u64 {
+        QUOTA.with(|quota| *quota.borrow())
+    }
+
+    pub fn set_quota(bytes: u64) {
+        QUOTA.with(|quota| *quota.borrow_mut() = bytes);
+    }
+
+    pub fn save_upgrade_state(bytes: Vec<u8>) {
+        let memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(UPGRADE_STATE_MEMORY));
+        let mut cell = StableCell::init(memory, Vec::new()).expect("Failed to open upgrade state");
+        cell.set(bytes).expect("Failed to save upgrade state");
+    }
+
+    pub fn load_upgrade_state() -> Vec<u8> {
+        let memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(UPGRADE_STATE_MEMORY));
+        StableCell::init(memory, Vec::new()).expect("Failed to load upgrade state").get().clone()
+    }
+
+    pub fn save_schema_version(version: u32) {
+        let memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(SCHEMA_VERSION_MEMORY));
+        let mut cell = StableCell::init(memory, 0).expect("Failed to open schema version");
+        cell.set(version).expect("Failed to save schema version");
+    }
+
+    /// Schema version recorded with the upgrade state, if the code that wrote it recorded one.
+    pub fn load_schema_version() -> Option<u32> {
+        let memory = MEMORY_MANAGER.with(|manager| manager.borrow().get(SCHEMA_VERSION_MEMORY));
+        let version = *StableCell::init(memory, 0u32).expect("Failed to load schema version").get();
+        Some(version).filter(|version| *version != 0)
+    }
+
+    /// Whether stable memory still holds a `stable_save` blob instead of the memory manager layout.
+    pub fn has_legacy_layout() -> bool {
+        if ic_cdk::api::stable::stable64_size() == 0 {
+            return false;
+        }
+        let mut magic = [0u8; 4];
+        ic_cdk::api::stable::stable64_read(0, &mut magic);
+        &magic == b"DIDL"
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn document_key_round_trips() {
+            let key = DocumentKey::new("settings".to_string(), "theme/dark".to_string()).unwrap();
+            assert_eq!(DocumentKey::from_bytes(key.to_bytes()), key);
+        }
+
+        #[test]
+        fn lists_by_prefix_within_a_namespace() {
+            set_quota(DEFAULT_QUOTA);
+            put("a".to_string(), "x/1".to_string(), vec![1]).unwrap();
+            put("a".to_string(), "x/2".to_string(), vec![2, 2]).unwrap();
+            put("a".to_string(), "y/1".to_string(), vec![3]).unwrap();
+            put("b".to_string(), "x/3".to_string(), vec![4]).unwrap();
+
+            let keys: Vec<_> = list("a".to_string(), "x/", None).unwrap().collect();
+            assert_eq!(keys, vec![("x/1".to_string(), 1), ("x/2".to_string(), 2)]);
+            let after: Vec<_> = list("a".to_string(), "x/", Some("x/1".to_string())).unwrap().collect();
+            assert_eq!(after, vec![("x/2".to_string(), 2)]);
+        }
+
+        #[test]
+        fn exports_pages_by_size() {
+            set_quota(DEFAULT_QUOTA);
+            put("e".to_string(), "1".to_string(), vec![0; 10]).unwrap();
+            put("e".to_string(), "2".to_string(), vec![0; 10]).unwrap();
+            put("e".to_string(), "3".to_string(), vec![0; 30]).unwrap();
+
+            let (page, more) = export(None, 25).unwrap();
+            assert_eq!(page.len(), 2);
+            assert!(more);
+            let (page, more) = export(Some(("e".to_string(), "2".to_string())), 25).unwrap();
+            assert_eq!(page.len(), 1, "a document larger than the page still gets exported");
+            assert!(!more);
+        }
+
+        #[test]
+        fn clear_removes_every_document() {
+            put("notes".to_string(), "a".to_string(), vec![1; 10]).unwrap();
+            put("photos".to_string(), "b".to_string(), vec![2; 10]).unwrap();
+            clear();
+            assert_eq!(count(), 0);
+            assert_eq!(used(), 0);
+            assert_eq!(get("notes".to_string(), "a".to_string()).unwrap(), None);
+        }
+
+        #[test]
+        fn records_the_schema_version() {
+            assert_eq!(load_schema_version(), None);
+            save_schema_version(1);
+            assert_eq!(load_schema_version(), Some(1));
+        }
+
+        #[test]
+        fn enforces_the_quota() {
+            set_quota(8);
+            assert!(put("q".to_string(), "k".to_string(), vec![0; 4]).is_ok());
+            assert!(matches!(
+                put("q".to_string(), "k2".to_string(), vec![0; 4]),
+                Err(UserError::QuotaExceeded { .. })
+            ));
+            // Replacing a document only counts the difference.
+            assert!(put("q".to_string(), "k".to_string(), vec![0; 6]).is_ok());
+            assert!(delete("q".to_string(), "k".to_string()).unwrap());
+            assert_eq!(used(), 0);
+        }
+    }
+}
+
+/// Ordered steps that turn the upgrade state written by older code into the current `UpgradeState`.
+mod migrations {
+    use ic_cdk::export::candid::de::IDLDeserialize;
+    use ic_cdk::export::candid::utils::ArgumentDecoder;
+    use ic_cdk::export::candid::Encode;
+
+    use ic_cdk::export::Principal;
+
+    use super::{storage, Access, InitArgs, UpgradeState, User, SCHEMA_VERSION};
+
+    /// The upgrade call, for state that has to be rebuilt from it.
+    pub struct Upgrade<'a> {
+        /// Whoever upgrades the canister, the backend for canisters it manages.
+        pub caller: Principal,
+        pub args: Option<&'a InitArgs>,
+    }
+
+    type Step = fn(Vec<u8>, &Upgrade) -> Result<Vec<u8>, String>;
+
+    /// `STEPS[n]` migrates state of schema version `n` to version `n + 1`.
+    const STEPS: [Step; SCHEMA_VERSION as usize] = [from_empty, from_stable_save];
+
+    /// Run every step from `version` up to `SCHEMA_VERSION` on `state`.
+    pub fn migrate(version: u32, mut state: Vec<u8>, upgrade: &Upgrade) -> Result<Vec<u8>, String> {
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "Stored data has schema version {}, newer than schema version {} of this code",
+                version, SCHEMA_VERSION
+            ));
+        }
+        for (from, step) in STEPS.iter().enumerate().skip(version as usize) {
+            state = step(state, upgrade).map_err(|error| {
+                format!("Failed to migrate from schema version {} to {}: {}", from, from + 1, error)
+            })?;
+        }
+        Ok(state)
+    }
+
+    /// 0 → 1: the first code kept the profile on the heap only and left stable memory empty, so
+    /// the profile is gone. Access starts over with the owner named by the upgrade arguments.
+    fn from_empty(_: Vec<u8>, upgrade: &Upgrade) -> Result<Vec<u8>, String> {
+        let args = upgrade
+            .args
+            .ok_or("Stable memory is empty; upgrade with init args naming the owner")?;
+        let access = Access {
+            owner: args.owner,
+            backend: upgrade.caller,
+            governance: args.governance,
+        };
+        Encode!(&User::default(), &access).map_err(|e| e.to_string())
+    }
+
+    /// 1 → 2: profile and access were saved with `stable_save`, before documents were kept in
+    /// stable memory. The blob is followed by the rest of the stable memory page.
+    fn from_stable_save(state: Vec<u8>, _: &Upgrade) -> Result<Vec<u8>, String> {
+        let mut de = IDLDeserialize::new(&state).map_err(|e| e.to_string())?;
+        let (user, access): (User, Access) = ArgumentDecoder::decode(&mut de).map_err(|e| e.to_string())?;
+        let state = UpgradeState {
+            user,
+            access,
+            storage_quota: storage::DEFAULT_QUOTA,
+            public_profile: None,
+            read_only: None,
+        };
+        Encode!(&state).map_err(|e| e.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use ic_cdk::export::candid::Decode;
+        use ic_cdk::export::Principal;
+
+        fn user() -> User {
+            User {
+                name: "James Fury".to_string(),
+                age: 28,
+                email: "dragon99steel@gmail.com".to_string(),
+            }
+        }
+
+        fn upgrade(args: Option<&InitArgs>) -> Upgrade<'_> {
+            Upgrade {
+                caller: Principal::management_canister(),
+                args,
+            }
+        }
+
+        #[test]
+        fn starts_empty_stable_memory_from_the_upgrade_args() {
+            let args = InitArgs {
+                owner: Principal::anonymous(),
+                governance: None,
+                storage_quota: None,
+            };
+            let state = Decode!(&migrate(0, Vec::new(), &upgrade(Some(&args))).unwrap(), UpgradeState).unwrap();
+            assert_eq!(state.user.name, "");
+            assert_eq!(state.access.owner, Principal::anonymous());
+            assert_eq!(state.access.backend, Principal::management_canister());
+            assert_eq!(state.storage_quota, storage::DEFAULT_QUOTA);
+
+            let error = migrate(0, Vec::new(), &upgrade(None)).unwrap_err();
+            assert!(error.contains("init args naming the owner"), "{}", error);
+        }
+
+        #[test]
+        fn migrates_stable_save_state() {
+            let access = Access {
+                owner: Principal::management_canister(),
+                ..Access::default()
+            };
+            let mut legacy = Encode!(&user(), &access).unwrap();
+            legacy.resize(64 * 1024, 0);
+
+            let state = Decode!(&migrate(1, legacy, &upgrade(None)).unwrap(), UpgradeState).unwrap();
+            assert_eq!(state.user.name, "James Fury");
+            assert_eq!(state.access.owner, Principal::management_canister());
+            assert_eq!(state.storage_quota, storage::DEFAULT_QUOTA);
+        }
+
+        #[test]
+        fn keeps_current_state() {
+            let state = Encode!(&UpgradeState {
+                user: user(),
+                access: Access::default(),
+                storage_quota: 1,
+                public_profile: Some(true),
+                read_only: Some(false),
+            })
+            .unwrap();
+            assert_eq!(migrate(SCHEMA_VERSION, state.clone(), &upgrade(None)).unwrap(), state);
+        }
+
+        #[test]
+        fn refuses_newer_or_broken_state() {
+            assert!(migrate(SCHEMA_VERSION + 1, Vec::new(), &upgrade(None)).is_err());
+            let error = migrate(1, b"not candid".to_vec(), &upgrade(None)).unwrap_err();
+            assert!(error.starts_with("Failed to migrate from schema version 1 to 2"));
+        }
+    }
+}
+
+/// Checks the balance on the global timer and asks the backend for cycles when it runs low,
+/// so the backend does not have to poll every user canister.
+mod cycles {
+    use std::cell::RefCell;
+
+    use ic_cdk::api;
+    use ic_cdk::export::candid::{CandidType, Decode, Deserialize, Encode, Reserved};
+
+    use super::ACCESS;
+
+    const CHECK_INTERVAL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+    /// Same as the backend's fleet threshold for low cycles.
+    const LOW_CYCLES_THRESHOLD: u128 = 10_000_000_000;
+
+    // Mirrors the backend's `Error`. Candid only decodes it if every variant is listed; the
+    // ones `request_top_up` does not return are left undecoded.
+    #[derive(CandidType, Debug, Deserialize)]
+    enum BackendError {
+        NotAuthorized,
+        NotFound(String),
+        InvalidArgument(String),
+        InsufficientCycles { available: u128, required: u128 },
+        ManagementCallFailed { method: String, message: String },
+        CanisterCallFailed(Reserved),
+        UserCanisterError(Reserved),
+        DownloadFailed(Reserved),
+        InvalidWasm(Reserved),
+        Validation(Reserved),
+        SignupInProgress(Reserved),
+        Busy(String),
+        SignupFailed(Reserved),
+        TopUpRefused(String),
+        WalletDeployFailed(Reserved),
+    }
+
+    /// The most recent top-up request, as reported by `health`.
+    #[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+    pub struct TopUpAttempt {
+        /// When the request was sent, in nanoseconds since the epoch.
+        at: u64,
+        /// The balance that triggered the request.
+        balance: u128,
+        /// The cycles received, or why none were.
+        outcome: Result<u128, String>,
+    }
+
+    thread_local! {
+        // Heap only: it describes the current run and starts empty again after an upgrade.
+        static LAST_TOP_UP: RefCell<Option<TopUpAttempt>> = const { RefCell::new(None) };
+    }
+
+    pub fn last_top_up() -> Option<TopUpAttempt> {
+        LAST_TOP_UP.with(|last| last.borrow().clone())
+    }
+
+    /// Arm the global timer for the next balance check.
+    pub fn schedule() {
+        let next = api::time().saturating_add(CHECK_INTERVAL_NANOS);
+        // SAFETY: ic0.global_timer_set is always a safe call.
+        unsafe { ic0::global_timer_set(next as i64) };
+    }
+
+    // Called by the IC at or after the time passed to `ic0.global_timer_set`.
+    #[export_name = "canister_global_timer"]
+    extern "C" fn global_timer() {
+        ic_cdk::setup();
+        // Armed before the check, so a failing top-up request does not stop later checks.
+        schedule();
+        let balance = api::canister_balance128();
+        if balance < LOW_CYCLES_THRESHOLD {
+            ic_cdk::spawn(request_top_up(balance));
+        }
+    }
+
+    async fn request_top_up(balance: u128) {
+        let (owner, backend) = ACCESS.with(|access| (access.borrow().owner, access.borrow().backend));
+        if backend == owner {
+            // Self-custodied: there is no backend to ask.
+            return;
+        }
+        let at = api::time();
+        let outcome = match api::call::call_raw(backend, "request_top_up", Encode!().unwrap(), 0).await {
+            Ok(reply) => describe_reply(&reply),
+            Err((code, msg)) => Err(format!("{:?}: {}", code, msg)),
+        };
+        LAST_TOP_UP.with(|last| *last.borrow_mut() = Some(TopUpAttempt { at, balance, outcome }));
+    }
+
+    fn describe_reply(reply: &[u8]) -> Result<u128, String> {
+        if let Ok(reply) = Decode!(reply, Result<u128, BackendError>) {
+            return reply.map_err(|error| format!("{:?}", error));
+        }
+        // A backend with variants added since fails to decode above.
+        match Decode!(reply, Result<u128, Reserved>) {
+            Ok(Ok(cycles)) => Ok(cycles),
+            Ok(Err(_)) => Err("Unknown error".to_string()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Stand in for the backend's `Error`, as mirrored and with a variant added since.
+        #[derive(CandidType)]
+        enum Error {
+            TopUpRefused(String),
+        }
+
+        #[derive(CandidType)]
+        enum NewerError {
+            Unexpected,
+        }
+
+        #[test]
+        fn backend_refusals_are_kept_as_text() {
+            let refusal: Result<u128, Error> = Err(Error::TopUpRefused("Limit reached".to_string()));
+            let outcome = describe_reply(&Encode!(&refusal).unwrap()).unwrap_err();
+            assert_eq!(outcome, r#"TopUpRefused("Limit reached")"#);
+
+            let granted: Result<u128, Error> = Ok(50);
+            assert_eq!(describe_reply(&Encode!(&granted).unwrap()), Ok(50));
+
+            let newer: Result<u128, NewerError> = Err(NewerError::Unexpected);
+            assert_eq!(describe_reply(&Encode!(&newer).unwrap()), Err("Unknown error".to_string()));
+            let newer_granted: Result<u128, NewerError> = Ok(50);
+            assert_eq!(describe_reply(&Encode!(&newer_granted).unwrap()), Ok(50));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_published_profiles_show_the_name() {
+        let user = User {
+            name: "James Fury".to_string(),
+            age: 28,
+            email: "dragon99steel@gmail.com".to_string(),
+        };
+        assert!(public_view(&user, false).is_empty());
+        let public = public_view(&user, true);
+        assert_eq!(public.name, "James Fury");
+        assert_eq!(public.age, 0);
+        assert!(public.email.is_empty());
+    }
+
+    #[test]
+    fn read_only_canisters_refuse_writes() {
+        assert!(ensure_writable().is_ok());
+        READ_ONLY.with(|read_only| read_only.replace(true));
+        assert!(matches!(ensure_writable(), Err(UserError::ReadOnly)));
+    }
+
+    #[test]
+    fn version_section_matches_version() {
+        let metadata = std::str::from_utf8(&VERSION_SECTION).unwrap();
+        assert!(metadata.starts_with(&format!("version={}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(metadata.ends_with(&format!("schema_version={}\n", SCHEMA_VERSION)));
+    }
+}
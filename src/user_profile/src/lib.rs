@@ -0,0 +1,179 @@
+//! Validation rules for user profiles, shared by the backend and the user canister.
+use candid::{CandidType, Deserialize};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt;
+
+pub const MAX_NAME_LENGTH: usize = 100;
+pub const MIN_AGE: u64 = 1;
+pub const MAX_AGE: u64 = 150;
+/// Longest address allowed by RFC 5321.
+pub const MAX_EMAIL_LENGTH: usize = 254;
+pub const MAX_EMAIL_LOCAL_PART_LENGTH: usize = 64;
+
+lazy_static! {
+    // RFC 5322 dot-atom local part and a domain made of RFC 1035 labels with at least one dot.
+    static ref EMAIL_RE: Regex = Regex::new(
+        r"^[A-Za-z0-9!#$%&'*+/=?^_`{|}~-]+(\.[A-Za-z0-9!#$%&'*+/=?^_`{|}~-]+)*@[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?(\.[A-Za-z0-9]([A-Za-z0-9-]{0,61}[A-Za-z0-9])?)+$"
+    )
+    .unwrap();
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileField {
+    #[serde(rename = "name")]
+    Name,
+    #[serde(rename = "age")]
+    Age,
+    #[serde(rename = "email")]
+    Email,
+}
+
+impl fmt::Display for ProfileField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProfileField::Name => "name",
+            ProfileField::Age => "age",
+            ProfileField::Email => "email",
+        })
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: ProfileField,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: ProfileField, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Check every field of a profile and return one error per failing field.
+pub fn validate_user(name: &str, age: u64, email: &str) -> Result<(), Vec<FieldError>> {
+    let errors: Vec<FieldError> = [validate_name(name), validate_age(age), validate_email(email)]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check only the fields a partial update sets.
+pub fn validate_patch(name: Option<&str>, age: Option<u64>, email: Option<&str>) -> Result<(), Vec<FieldError>> {
+    let errors: Vec<FieldError> = [
+        name.map(validate_name),
+        age.map(validate_age),
+        email.map(validate_email),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(Result::err)
+    .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), FieldError> {
+    if name.trim().is_empty() {
+        return Err(FieldError::new(ProfileField::Name, "must not be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(FieldError::new(
+            ProfileField::Name,
+            format!("must be at most {MAX_NAME_LENGTH} characters"),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(FieldError::new(ProfileField::Name, "must not contain control characters"));
+    }
+    Ok(())
+}
+
+pub fn validate_age(age: u64) -> Result<(), FieldError> {
+    if !(MIN_AGE..=MAX_AGE).contains(&age) {
+        return Err(FieldError::new(
+            ProfileField::Age,
+            format!("must be between {MIN_AGE} and {MAX_AGE}"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_email(email: &str) -> Result<(), FieldError> {
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(FieldError::new(
+            ProfileField::Email,
+            format!("must be at most {MAX_EMAIL_LENGTH} characters"),
+        ));
+    }
+    let local_part_too_long = email
+        .split_once('@')
+        .is_some_and(|(local, _)| local.len() > MAX_EMAIL_LOCAL_PART_LENGTH);
+    if local_part_too_long || !EMAIL_RE.is_match(email) {
+        return Err(FieldError::new(ProfileField::Email, "must be a valid email address"));
+    }
+    Ok(())
+}
+
+/// Render errors as "field: message; ..." for interfaces that only carry text.
+pub fn describe(errors: &[FieldError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{}: {}", error.field, error.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_valid_profile() {
+        assert_eq!(validate_user("James Fury", 28, "dragon99steel@gmail.com"), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_failing_field() {
+        let errors = validate_user(" ", 0, "not-an-email").unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec![ProfileField::Name, ProfileField::Age, ProfileField::Email]);
+    }
+
+    #[test]
+    fn patch_checks_only_set_fields() {
+        assert_eq!(validate_patch(None, None, Some("new@example.com")), Ok(()));
+        let errors = validate_patch(None, Some(0), Some("nope")).unwrap_err();
+        let fields: Vec<_> = errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec![ProfileField::Age, ProfileField::Email]);
+    }
+
+    #[test]
+    fn enforces_length_limits() {
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH)).is_ok());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        let local = "a".repeat(MAX_EMAIL_LOCAL_PART_LENGTH + 1);
+        assert!(validate_email(&format!("{local}@example.com")).is_err());
+    }
+
+    #[test]
+    fn checks_email_syntax() {
+        assert!(validate_email("first.last+tag@sub.example.org").is_ok());
+        assert!(validate_email("\ndragon99steel@gmail.com").is_err());
+        assert!(validate_email("user@localhost").is_err());
+        assert!(validate_email("user..name@example.com").is_err());
+        assert!(validate_email("user@-example.com").is_err());
+    }
+}